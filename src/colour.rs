@@ -0,0 +1,563 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+pub type Rgb = (u8, u8, u8);
+
+/// An RGB colour with fractional channels on the same 0 to 255 scale as `Rgb`
+pub type PreciseRgb = (f32, f32, f32);
+
+pub fn lerp_f32(start: f32, end: f32, factor: f32) -> f32 {
+    start + (end - start) * factor
+}
+
+fn to_channel(x: f32) -> f32 {
+    (x * 255.0).clamp(0.0, 255.0)
+}
+
+pub fn round_rgb((r, g, b): PreciseRgb) -> Rgb {
+    (r.round() as u8, g.round() as u8, b.round() as u8)
+}
+
+/// Which way round the colour wheel to go when interpolating hues
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum HueDirection {
+    #[default]
+    Shortest,
+    Longest,
+    Increasing,
+    Decreasing,
+}
+
+/// The colour space transitions interpolate in. Saved as the same names it
+/// is parsed from
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum BlendSpace {
+    #[default]
+    Rgb,
+    Hsv(HueDirection),
+    Hsl(HueDirection),
+    /// Perceptually uniform, blending lightness evenly along a straight line
+    Oklab,
+    /// The polar form of OKLab, moving round the hue wheel at even lightness
+    Oklch(HueDirection),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseBlendSpaceError(String);
+
+impl fmt::Display for ParseBlendSpaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown blend space {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseBlendSpaceError {}
+
+impl FromStr for HueDirection {
+    type Err = ParseBlendSpaceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "shortest" => Ok(Self::Shortest),
+            "longest" => Ok(Self::Longest),
+            "increasing" => Ok(Self::Increasing),
+            "decreasing" => Ok(Self::Decreasing),
+            _ => Err(ParseBlendSpaceError(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for BlendSpace {
+    type Err = ParseBlendSpaceError;
+
+    /// Parses names like `rgb`, `oklab` or `hsl-increasing`. The hue direction
+    /// defaults to the shortest path, and an empty string is treated as RGB
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_ascii_lowercase();
+        let (space, direction) = lower.split_once('-').unwrap_or((&lower, ""));
+        let err = |_| ParseBlendSpaceError(s.to_string());
+        match space {
+            "" | "rgb" if direction.is_empty() => Ok(Self::Rgb),
+            "hsv" => Ok(Self::Hsv(direction.parse().map_err(err)?)),
+            "hsl" => Ok(Self::Hsl(direction.parse().map_err(err)?)),
+            "oklab" if direction.is_empty() => Ok(Self::Oklab),
+            "oklch" => Ok(Self::Oklch(direction.parse().map_err(err)?)),
+            _ => Err(ParseBlendSpaceError(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for HueDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Shortest => "shortest",
+            Self::Longest => "longest",
+            Self::Increasing => "increasing",
+            Self::Decreasing => "decreasing",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for BlendSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rgb => f.write_str("rgb"),
+            Self::Hsv(direction) => write!(f, "hsv-{}", direction),
+            Self::Hsl(direction) => write!(f, "hsl-{}", direction),
+            Self::Oklab => f.write_str("oklab"),
+            Self::Oklch(direction) => write!(f, "oklch-{}", direction),
+        }
+    }
+}
+
+impl From<BlendSpace> for String {
+    fn from(blend: BlendSpace) -> Self {
+        blend.to_string()
+    }
+}
+
+impl TryFrom<String> for BlendSpace {
+    type Error = ParseBlendSpaceError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Hue in degrees, chroma, and the max and min of the channels, all scaled
+/// between 0 and 1 apart from the hue
+fn hue_chroma((r, g, b): Rgb) -> (f32, f32, f32, f32) {
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let chroma = max - min;
+    let hue = if chroma == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / chroma + 2.0)
+    } else {
+        60.0 * ((r - g) / chroma + 4.0)
+    };
+    (hue, chroma, max, min)
+}
+
+fn from_hue_chroma(hue: f32, chroma: f32, min: f32) -> PreciseRgb {
+    let h = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (h.rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match h as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    (
+        to_channel(r + min),
+        to_channel(g + min),
+        to_channel(b + min),
+    )
+}
+
+/// Hue in degrees, then saturation and value between 0 and 1
+pub fn rgb_to_hsv(rgb: Rgb) -> (f32, f32, f32) {
+    let (hue, chroma, max, _) = hue_chroma(rgb);
+    let saturation = if max == 0.0 { 0.0 } else { chroma / max };
+    (hue, saturation, max)
+}
+
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> Rgb {
+    round_rgb(hsv_to_precise(hue, saturation, value))
+}
+
+fn hsv_to_precise(hue: f32, saturation: f32, value: f32) -> PreciseRgb {
+    let saturation = saturation.clamp(0.0, 1.0);
+    let value = value.clamp(0.0, 1.0);
+    let chroma = value * saturation;
+    from_hue_chroma(hue, chroma, value - chroma)
+}
+
+/// Hue in degrees, then saturation and lightness between 0 and 1
+pub fn rgb_to_hsl(rgb: Rgb) -> (f32, f32, f32) {
+    let (hue, chroma, max, min) = hue_chroma(rgb);
+    let lightness = (max + min) / 2.0;
+    let saturation = if lightness <= 0.0 || lightness >= 1.0 {
+        0.0
+    } else {
+        chroma / (1.0 - (2.0 * lightness - 1.0).abs())
+    };
+    (hue, saturation, lightness)
+}
+
+pub fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> Rgb {
+    round_rgb(hsl_to_precise(hue, saturation, lightness))
+}
+
+fn hsl_to_precise(hue: f32, saturation: f32, lightness: f32) -> PreciseRgb {
+    let saturation = saturation.clamp(0.0, 1.0);
+    let lightness = lightness.clamp(0.0, 1.0);
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    from_hue_chroma(hue, chroma, lightness - chroma / 2.0)
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    to_channel(if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    })
+}
+
+/// Lightness, then the a and b axes. See https://bottosson.github.io/posts/oklab/
+pub fn rgb_to_oklab((r, g, b): Rgb) -> (f32, f32, f32) {
+    let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+    let l = (0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+    (
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    )
+}
+
+fn oklab_to_linear(lightness: f32, a: f32, b: f32) -> (f32, f32, f32) {
+    let l = (lightness + 0.396_337_78 * a + 0.215_803_76 * b).powi(3);
+    let m = (lightness - 0.105_561_346 * a - 0.063_854_17 * b).powi(3);
+    let s = (lightness - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+    (
+        4.076_741_7 * l - 3.307_711_6 * m + 0.230_969_94 * s,
+        -1.268_438 * l + 2.609_757_4 * m - 0.341_319_38 * s,
+        -0.004_196_086_3 * l - 0.703_418_6 * m + 1.707_614_7 * s,
+    )
+}
+
+pub fn oklab_to_rgb(lightness: f32, a: f32, b: f32) -> Rgb {
+    round_rgb(oklab_to_precise(lightness, a, b))
+}
+
+fn oklab_to_precise(lightness: f32, a: f32, b: f32) -> PreciseRgb {
+    let (r, g, b) = oklab_to_linear(lightness, a, b);
+    (linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b))
+}
+
+/// As `oklab_to_rgb`, but colours the LEDs can't show have their chroma
+/// reduced until they fit rather than having each channel clipped, which
+/// would change their lightness
+fn oklab_to_precise_in_gamut(lightness: f32, a: f32, b: f32) -> PreciseRgb {
+    let in_gamut = |scale: f32| {
+        let (r, g, b) = oklab_to_linear(lightness, a * scale, b * scale);
+        [r, g, b].iter().all(|c| (-1e-4..=1.0 + 1e-4).contains(c))
+    };
+    if in_gamut(1.0) {
+        return oklab_to_precise(lightness, a, b);
+    }
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..16 {
+        let mid = (low + high) / 2.0;
+        if in_gamut(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    oklab_to_precise(lightness, a * low, b * low)
+}
+
+/// Hue in degrees, chroma, then lightness. This is backwards compared to the
+/// usual LCh order so that it lines up with HSV and HSL
+fn rgb_to_oklch(rgb: Rgb) -> (f32, f32, f32) {
+    let (lightness, a, b) = rgb_to_oklab(rgb);
+    (
+        b.atan2(a).to_degrees().rem_euclid(360.0),
+        a.hypot(b),
+        lightness,
+    )
+}
+
+#[cfg(test)]
+fn oklch_to_rgb(hue: f32, chroma: f32, lightness: f32) -> Rgb {
+    round_rgb(oklch_to_precise(hue, chroma, lightness))
+}
+
+fn oklch_to_precise(hue: f32, chroma: f32, lightness: f32) -> PreciseRgb {
+    let (sin, cos) = hue.to_radians().sin_cos();
+    oklab_to_precise_in_gamut(lightness, chroma * cos, chroma * sin)
+}
+
+fn lerp_hue(start: f32, end: f32, factor: f32, direction: HueDirection) -> f32 {
+    let mut delta = (end - start).rem_euclid(360.0);
+    match direction {
+        HueDirection::Shortest if delta > 180.0 => delta -= 360.0,
+        HueDirection::Longest if delta < 180.0 && delta > 0.0 => delta -= 360.0,
+        HueDirection::Decreasing if delta > 0.0 => delta -= 360.0,
+        _ => (),
+    }
+    (start + delta * factor).rem_euclid(360.0)
+}
+
+/// Interpolate between two colours in a space with a hue, saturation and one
+/// more component. Greys have no meaningful hue, so they borrow the other
+/// colour's hue to avoid sweeping through unrelated colours on the way. Where
+/// the third component makes saturation meaningless too (black, say), that's
+/// borrowed as well so fades keep their colour
+fn lerp_cylindrical(
+    init: (f32, f32, f32),
+    target: (f32, f32, f32),
+    factor: f32,
+    direction: HueDirection,
+    no_saturation: fn(f32) -> bool,
+) -> (f32, f32, f32) {
+    let (mut h1, mut s1, x1) = init;
+    let (mut h2, mut s2, x2) = target;
+    // allow for rounding errors in the conversions that leave greys very
+    // slightly saturated
+    let grey1 = s1 < 1e-4 || no_saturation(x1);
+    let grey2 = s2 < 1e-4 || no_saturation(x2);
+    if grey1 {
+        h1 = h2;
+    }
+    if grey2 {
+        h2 = h1;
+    }
+    if no_saturation(x1) {
+        s1 = s2;
+    }
+    if no_saturation(x2) {
+        s2 = s1;
+    }
+    (
+        lerp_hue(h1, h2, factor, direction),
+        lerp_f32(s1, s2, factor),
+        lerp_f32(x1, x2, factor),
+    )
+}
+
+impl BlendSpace {
+    /// Blend from `init` to `target`, `factor` of the way along
+    pub fn mix(&self, init: Rgb, target: Rgb, factor: f32) -> Rgb {
+        round_rgb(self.mix_precise(init, target, factor))
+    }
+
+    /// As `mix`, without rounding the result
+    pub fn mix_precise(&self, init: Rgb, target: Rgb, factor: f32) -> PreciseRgb {
+        match *self {
+            Self::Rgb => (
+                lerp_f32(init.0 as f32, target.0 as f32, factor),
+                lerp_f32(init.1 as f32, target.1 as f32, factor),
+                lerp_f32(init.2 as f32, target.2 as f32, factor),
+            ),
+            Self::Hsv(direction) => {
+                let (h, s, v) = lerp_cylindrical(
+                    rgb_to_hsv(init),
+                    rgb_to_hsv(target),
+                    factor,
+                    direction,
+                    |v| v == 0.0,
+                );
+                hsv_to_precise(h, s, v)
+            }
+            Self::Hsl(direction) => {
+                let (h, s, l) = lerp_cylindrical(
+                    rgb_to_hsl(init),
+                    rgb_to_hsl(target),
+                    factor,
+                    direction,
+                    |l| l == 0.0 || l == 1.0,
+                );
+                hsl_to_precise(h, s, l)
+            }
+            Self::Oklab => {
+                let (l1, a1, b1) = rgb_to_oklab(init);
+                let (l2, a2, b2) = rgb_to_oklab(target);
+                oklab_to_precise_in_gamut(
+                    lerp_f32(l1, l2, factor),
+                    lerp_f32(a1, a2, factor),
+                    lerp_f32(b1, b2, factor),
+                )
+            }
+            Self::Oklch(direction) => {
+                let (h, c, l) = lerp_cylindrical(
+                    rgb_to_oklch(init),
+                    rgb_to_oklch(target),
+                    factor,
+                    direction,
+                    |l| l <= 0.0,
+                );
+                oklch_to_precise(h, c, l)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const COLOURS: [Rgb; 8] = [
+        (0, 0, 0),
+        (255, 255, 255),
+        (255, 0, 0),
+        (0, 255, 0),
+        (0, 0, 255),
+        (255, 165, 0),
+        (75, 0, 130),
+        (12, 200, 99),
+    ];
+
+    #[test]
+    fn test_hsv_round_trip() {
+        for rgb in COLOURS {
+            let (h, s, v) = rgb_to_hsv(rgb);
+            assert_eq!(hsv_to_rgb(h, s, v), rgb);
+        }
+    }
+
+    #[test]
+    fn test_hsl_round_trip() {
+        for rgb in COLOURS {
+            let (h, s, l) = rgb_to_hsl(rgb);
+            assert_eq!(hsl_to_rgb(h, s, l), rgb);
+        }
+    }
+
+    #[test]
+    fn test_hsv_red_to_blue_stays_bright() {
+        // the shortest way from red to blue is through magenta
+        let halfway = BlendSpace::Hsv(HueDirection::Shortest).mix((255, 0, 0), (0, 0, 255), 0.5);
+        assert_eq!(halfway, (255, 0, 255));
+        // whereas RGB goes through a dark purple
+        assert_eq!(
+            BlendSpace::Rgb.mix((255, 0, 0), (0, 0, 255), 0.5),
+            (128, 0, 128)
+        );
+    }
+
+    #[test]
+    fn test_hue_directions() {
+        let red = (255, 0, 0);
+        let blue = (0, 0, 255);
+        // increasing from red at 0 degrees passes green at 120 degrees
+        assert_eq!(
+            BlendSpace::Hsv(HueDirection::Increasing).mix(red, blue, 0.5),
+            (0, 255, 0)
+        );
+        assert_eq!(
+            BlendSpace::Hsv(HueDirection::Longest).mix(red, blue, 0.5),
+            (0, 255, 0)
+        );
+        assert_eq!(
+            BlendSpace::Hsl(HueDirection::Decreasing).mix(red, blue, 0.5),
+            (255, 0, 255)
+        );
+    }
+
+    #[test]
+    fn test_fade_to_black_keeps_hue() {
+        let halfway = BlendSpace::Hsv(HueDirection::Shortest).mix((0, 255, 0), (0, 0, 0), 0.5);
+        assert_eq!(halfway, (0, 128, 0));
+    }
+
+    #[test]
+    fn test_oklab_round_trip() {
+        for rgb in COLOURS {
+            let (l, a, b) = rgb_to_oklab(rgb);
+            assert_eq!(oklab_to_rgb(l, a, b), rgb);
+            let (h, c, l) = rgb_to_oklch(rgb);
+            assert_eq!(oklch_to_rgb(h, c, l), rgb);
+        }
+    }
+
+    #[test]
+    fn test_oklab_reference_values() {
+        let (l, a, b) = rgb_to_oklab((255, 255, 255));
+        assert!((l - 1.0).abs() < 0.001 && a.abs() < 0.001 && b.abs() < 0.001);
+        let (l, a, b) = rgb_to_oklab((255, 0, 0));
+        assert!((l - 0.628).abs() < 0.001);
+        assert!((a - 0.2249).abs() < 0.001);
+        assert!((b - 0.1258).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_oklab_blue_to_green() {
+        let blue = (0, 0, 255);
+        let green = (0, 255, 0);
+        let (l_blue, _, _) = rgb_to_oklab(blue);
+        let (l_green, _, _) = rgb_to_oklab(green);
+        for space in [BlendSpace::Oklab, BlendSpace::Oklch(HueDirection::Shortest)] {
+            assert_eq!(space.mix(blue, green, 0.0), blue);
+            assert_eq!(space.mix(blue, green, 1.0), green);
+            // lightness moves evenly between the two ends
+            for factor in [0.25, 0.5, 0.75] {
+                let (l, _, _) = rgb_to_oklab(space.mix(blue, green, factor));
+                let expected = l_blue + (l_green - l_blue) * factor;
+                assert!((l - expected).abs() < 0.02, "{:?} at {}", space, factor);
+            }
+        }
+    }
+
+    #[test]
+    fn test_oklch_fade_to_black_keeps_hue() {
+        let (hue, _, _) = rgb_to_oklch((255, 0, 0));
+        let halfway = BlendSpace::Oklch(HueDirection::Shortest).mix((255, 0, 0), (0, 0, 0), 0.5);
+        let (halfway_hue, _, _) = rgb_to_oklch(halfway);
+        assert!((hue - halfway_hue).abs() < 2.0);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("".parse(), Ok(BlendSpace::Rgb));
+        assert_eq!("RGB".parse(), Ok(BlendSpace::Rgb));
+        assert_eq!("hsv".parse(), Ok(BlendSpace::Hsv(HueDirection::Shortest)));
+        assert_eq!(
+            "hsl-decreasing".parse(),
+            Ok(BlendSpace::Hsl(HueDirection::Decreasing))
+        );
+        assert_eq!("oklab".parse(), Ok(BlendSpace::Oklab));
+        assert_eq!(
+            "oklch-longest".parse(),
+            Ok(BlendSpace::Oklch(HueDirection::Longest))
+        );
+        assert!("oklab-longest".parse::<BlendSpace>().is_err());
+        assert!("rgb-longest".parse::<BlendSpace>().is_err());
+        assert!("hsv-sideways".parse::<BlendSpace>().is_err());
+        assert!("cmyk".parse::<BlendSpace>().is_err());
+    }
+
+    #[test]
+    fn test_names_parse_back() {
+        let directions = [
+            HueDirection::Shortest,
+            HueDirection::Longest,
+            HueDirection::Increasing,
+            HueDirection::Decreasing,
+        ];
+        let mut blends = vec![BlendSpace::Rgb, BlendSpace::Oklab];
+        for direction in directions {
+            blends.extend([
+                BlendSpace::Hsv(direction),
+                BlendSpace::Hsl(direction),
+                BlendSpace::Oklch(direction),
+            ]);
+        }
+        for blend in blends {
+            assert_eq!(blend.to_string().parse(), Ok(blend));
+        }
+    }
+}
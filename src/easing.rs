@@ -0,0 +1,251 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// How progress through a transition maps onto progress between colours.
+/// Saved as the same names it is parsed from
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Easing {
+    #[default]
+    Linear,
+    /// Quadratic, starting slowly
+    EaseIn,
+    /// Quadratic, finishing slowly
+    EaseOut,
+    /// Quadratic, starting and finishing slowly
+    EaseInOut,
+    /// Sinusoidal, starting and finishing slowly
+    Sine,
+    /// Cubic, starting and finishing slowly
+    Cubic,
+    /// Exponential, starting and finishing slowly
+    Exponential,
+    /// Hold the starting colour, then jump to the target at the end
+    Step,
+    /// CSS style cubic bezier with control points (x1, y1) and (x2, y2)
+    CubicBezier(f32, f32, f32, f32),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseEasingError(String);
+
+impl fmt::Display for ParseEasingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown easing {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseEasingError {}
+
+impl FromStr for Easing {
+    type Err = ParseEasingError;
+
+    /// Parses names like `ease-in-out` or `cubic-bezier(0.25, 0.1, 0.25, 1)`.
+    /// An empty string is treated as linear
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseEasingError(s.to_string());
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "linear" => Ok(Self::Linear),
+            "ease-in" => Ok(Self::EaseIn),
+            "ease-out" => Ok(Self::EaseOut),
+            "ease-in-out" => Ok(Self::EaseInOut),
+            "sine" => Ok(Self::Sine),
+            "cubic" => Ok(Self::Cubic),
+            "exponential" => Ok(Self::Exponential),
+            "step" => Ok(Self::Step),
+            other => {
+                let args = other
+                    .strip_prefix("cubic-bezier(")
+                    .and_then(|rest| rest.strip_suffix(')'))
+                    .ok_or_else(err)?;
+                let points = args
+                    .split(',')
+                    .map(|p| p.trim().parse::<f32>())
+                    .collect::<Result<Vec<f32>, _>>()
+                    .map_err(|_| err())?;
+                match points[..] {
+                    // the x values need to stay in range so that time only
+                    // ever moves forwards
+                    [x1, y1, x2, y2] if (0.0..=1.0).contains(&x1) && (0.0..=1.0).contains(&x2) => {
+                        Ok(Self::CubicBezier(x1, y1, x2, y2))
+                    }
+                    _ => Err(err()),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Easing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Linear => f.write_str("linear"),
+            Self::EaseIn => f.write_str("ease-in"),
+            Self::EaseOut => f.write_str("ease-out"),
+            Self::EaseInOut => f.write_str("ease-in-out"),
+            Self::Sine => f.write_str("sine"),
+            Self::Cubic => f.write_str("cubic"),
+            Self::Exponential => f.write_str("exponential"),
+            Self::Step => f.write_str("step"),
+            Self::CubicBezier(x1, y1, x2, y2) => {
+                write!(f, "cubic-bezier({}, {}, {}, {})", x1, y1, x2, y2)
+            }
+        }
+    }
+}
+
+impl From<Easing> for String {
+    fn from(easing: Easing) -> Self {
+        easing.to_string()
+    }
+}
+
+impl TryFrom<String> for Easing {
+    type Error = ParseEasingError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn bezier(p1: f32, p2: f32, s: f32) -> f32 {
+    let inv = 1.0 - s;
+    3.0 * inv * inv * s * p1 + 3.0 * inv * s * s * p2 + s * s * s
+}
+
+fn bezier_slope(p1: f32, p2: f32, s: f32) -> f32 {
+    let inv = 1.0 - s;
+    3.0 * inv * inv * p1 + 6.0 * inv * s * (p2 - p1) + 3.0 * s * s * (1.0 - p2)
+}
+
+fn cubic_bezier(x1: f32, y1: f32, x2: f32, y2: f32, t: f32) -> f32 {
+    // find the curve parameter that gives us an x of t, using Newton's
+    // method and falling back to bisection where the slope is too flat
+    let mut s = t;
+    for _ in 0..8 {
+        let slope = bezier_slope(x1, x2, s);
+        if slope.abs() < 1e-6 {
+            break;
+        }
+        s -= (bezier(x1, x2, s) - t) / slope;
+    }
+    if !(0.0..=1.0).contains(&s) || (bezier(x1, x2, s) - t).abs() > 1e-4 {
+        let (mut low, mut high) = (0.0, 1.0);
+        s = t;
+        for _ in 0..32 {
+            if bezier(x1, x2, s) < t {
+                low = s;
+            } else {
+                high = s;
+            }
+            s = (low + high) / 2.0;
+        }
+    }
+    bezier(y1, y2, s)
+}
+
+impl Easing {
+    /// Map a linear progress value between 0 and 1 onto the eased progress
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match *self {
+            Self::Linear => t,
+            Self::EaseIn => t * t,
+            Self::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Self::EaseInOut => {
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    1.0 - 2.0 * (1.0 - t) * (1.0 - t)
+                }
+            }
+            Self::Sine => (1.0 - (PI * t).cos()) / 2.0,
+            Self::Cubic => {
+                if t < 0.5 {
+                    4.0 * t * t * t
+                } else {
+                    1.0 - 4.0 * (1.0 - t) * (1.0 - t) * (1.0 - t)
+                }
+            }
+            Self::Exponential => {
+                if t <= 0.0 || t >= 1.0 {
+                    t
+                } else if t < 0.5 {
+                    2f32.powf(20.0 * t - 10.0) / 2.0
+                } else {
+                    1.0 - 2f32.powf(-20.0 * t + 10.0) / 2.0
+                }
+            }
+            Self::Step => {
+                if t < 1.0 {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+            Self::CubicBezier(x1, y1, x2, y2) => cubic_bezier(x1, y1, x2, y2, t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ALL: [Easing; 9] = [
+        Easing::Linear,
+        Easing::EaseIn,
+        Easing::EaseOut,
+        Easing::EaseInOut,
+        Easing::Sine,
+        Easing::Cubic,
+        Easing::Exponential,
+        Easing::Step,
+        Easing::CubicBezier(0.25, 0.1, 0.25, 1.0),
+    ];
+
+    #[test]
+    fn test_end_points_fixed() {
+        for easing in ALL {
+            assert!(easing.apply(0.0).abs() < 0.001, "{:?}", easing);
+            assert!((easing.apply(1.0) - 1.0).abs() < 0.001, "{:?}", easing);
+        }
+    }
+
+    #[test]
+    fn test_midpoints() {
+        assert_eq!(Easing::Linear.apply(0.5), 0.5);
+        assert_eq!(Easing::EaseIn.apply(0.5), 0.25);
+        assert_eq!(Easing::EaseOut.apply(0.5), 0.75);
+        assert!((Easing::Sine.apply(0.5) - 0.5).abs() < 0.001);
+        assert_eq!(Easing::Step.apply(0.99), 0.0);
+        // a linear bezier should come out linear
+        let bezier = Easing::CubicBezier(0.0, 0.0, 1.0, 1.0);
+        for t in [0.1, 0.3, 0.5, 0.9] {
+            assert!((bezier.apply(t) - t).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("".parse(), Ok(Easing::Linear));
+        assert_eq!("Ease-In-Out".parse(), Ok(Easing::EaseInOut));
+        assert_eq!(
+            "cubic-bezier(0.25, 0.1, 0.25, 1)".parse(),
+            Ok(Easing::CubicBezier(0.25, 0.1, 0.25, 1.0))
+        );
+        assert!("cubic-bezier(2, 0, 0, 1)".parse::<Easing>().is_err());
+        assert!("cubic-bezier(0, 0, 1)".parse::<Easing>().is_err());
+        assert!("bouncy".parse::<Easing>().is_err());
+    }
+
+    #[test]
+    fn test_names_parse_back() {
+        for easing in ALL {
+            assert_eq!(easing.to_string().parse(), Ok(easing));
+        }
+    }
+}
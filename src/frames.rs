@@ -1,10 +1,14 @@
-use std::sync::mpsc;
-use std::{thread, time};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::time;
 
-use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
 use serde::{Deserialize, Serialize};
 use zbus::zvariant::Type;
 
+use crate::colour::{self, lerp_f32, BlendSpace};
+use crate::easing::Easing;
+use crate::gamma::Gamma;
+use crate::output::{OutputBackend, OutputError};
+
 const MAX_BRIGHTNESS: u8 = 31;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -20,6 +24,99 @@ impl<T, E> Interrupted<T, E> {
     }
 }
 
+/// The slowest and fastest that steps can be played, as multiples of their
+/// normal speed
+pub const MIN_SPEED: f32 = 0.01;
+pub const MAX_SPEED: f32 = 100.0;
+
+/// A change of plan for a playing transition
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Control {
+    /// Stop the step where it has got to
+    Interrupt,
+    /// Hold the current frame, not counting the time until resuming as part of
+    /// the step
+    Pause,
+    Resume,
+    /// Scale the output of every LED by this, from 0 to 1
+    MasterBrightness(f32),
+    /// Play at this multiple of the normal speed
+    Speed(f32),
+}
+
+/// Where a playing transition hears about changes of plan
+pub trait Controls {
+    /// Wait up to `timeout`, or for as long as it takes if there isn't one,
+    /// for the next change of plan. Returning `None` early is fine
+    fn wait(&mut self, timeout: Option<time::Duration>) -> Option<Control>;
+
+    /// `Frames` to switch over to straight away, if new settings have
+    /// arrived since the last wait
+    fn reloaded(&mut self) -> Option<Frames> {
+        None
+    }
+}
+
+impl Controls for mpsc::Receiver<Control> {
+    fn wait(&mut self, timeout: Option<time::Duration>) -> Option<Control> {
+        let result = match timeout {
+            Some(timeout) => self.recv_timeout(timeout),
+            None => self.recv().map_err(mpsc::RecvTimeoutError::from),
+        };
+        match result {
+            Ok(control) => Some(control),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => panic!("Thread disconnected!"),
+        }
+    }
+}
+
+/// How far playback has got through a step, standing still while paused
+struct StepClock {
+    elapsed: f32,
+    last: time::Instant,
+    running: bool,
+    speed: f32,
+}
+
+impl StepClock {
+    fn new(speed: f32, running: bool) -> Self {
+        Self {
+            elapsed: 0.0,
+            last: time::Instant::now(),
+            running,
+            speed,
+        }
+    }
+
+    /// Seconds into the step, at normal speed
+    fn elapsed(&mut self) -> f32 {
+        let now = time::Instant::now();
+        if self.running {
+            self.elapsed += (now - self.last).as_secs_f32() * self.speed;
+        }
+        self.last = now;
+        self.elapsed
+    }
+
+    fn set_running(&mut self, running: bool) {
+        // count the time up to now at the old rate
+        self.elapsed();
+        self.running = running;
+    }
+
+    fn set_speed(&mut self, speed: f32) {
+        self.elapsed();
+        self.speed = speed;
+    }
+
+    /// Start counting the step again from zero
+    fn restart(&mut self) {
+        self.elapsed = 0.0;
+        self.last = time::Instant::now();
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Type)]
 pub struct LEDState {
     brightness: u8,
@@ -29,10 +126,6 @@ pub struct LEDState {
     pub time: f32,
 }
 
-fn lerp_single(start: u8, end: u8, factor: f32) -> u8 {
-    ((start as f32) * (1f32 - factor)) as u8 + ((end as f32) * factor) as u8
-}
-
 impl LEDState {
     pub fn new(brightness: u8, red: u8, green: u8, blue: u8, time: f32) -> Self {
         Self {
@@ -51,7 +144,30 @@ impl LEDState {
     pub fn from_hex(hex: &str, brightness: u8, time: f32) -> Result<Self, std::num::ParseIntError> {
         let u: u32 = u32::from_str_radix(hex, 16)?;
         let [_, red, green, blue] = u.to_be_bytes();
-        Ok(Self {brightness, red, green, blue, time})
+        Ok(Self {
+            brightness,
+            red,
+            green,
+            blue,
+            time,
+        })
+    }
+
+    /// Brightness, red, green, blue and time, in the same order as on D-Bus
+    pub fn as_tuple(&self) -> (u8, u8, u8, u8, f64) {
+        (
+            self.brightness,
+            self.red,
+            self.green,
+            self.blue,
+            self.time as f64,
+        )
+    }
+
+    /// Hue in degrees, saturation and value between 0 and 1
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32, brightness: u8, time: f32) -> Self {
+        let (red, green, blue) = colour::hsv_to_rgb(hue, saturation, value);
+        Self::new(brightness, red, green, blue, time)
     }
 
     fn cap_brightness(&self) -> Self {
@@ -65,17 +181,57 @@ impl LEDState {
         }
     }
 
+    #[cfg(test)]
     fn lerp(init: &Self, target: &Self, cur_time: f32) -> Self {
-        let t: f32 = cur_time / target.time;
+        Self::interpolate(init, target, cur_time, &Easing::Linear, &BlendSpace::Rgb)
+    }
+
+    #[cfg(test)]
+    fn interpolate(
+        init: &Self,
+        target: &Self,
+        cur_time: f32,
+        easing: &Easing,
+        blend: &BlendSpace,
+    ) -> Self {
+        let precise = Self::interpolate_precise(init, target, cur_time, easing, blend);
         Self {
-            brightness: lerp_single(init.brightness, target.brightness, t),
-            blue: lerp_single(init.blue, target.blue, t),
-            green: lerp_single(init.green, target.green, t),
-            red: lerp_single(init.red, target.red, t),
+            brightness: precise.brightness.round() as u8,
+            red: precise.red.round() as u8,
+            green: precise.green.round() as u8,
+            blue: precise.blue.round() as u8,
             time: cur_time,
         }
     }
 
+    fn interpolate_precise(
+        init: &Self,
+        target: &Self,
+        cur_time: f32,
+        easing: &Easing,
+        blend: &BlendSpace,
+    ) -> PreciseState {
+        // LEDs with shorter transitions than the rest of the step have
+        // already arrived, so hold them at their target
+        let progress: f32 = if target.time > 0.0 {
+            (cur_time / target.time).min(1.0)
+        } else {
+            1.0
+        };
+        let t = easing.apply(progress).clamp(0.0, 1.0);
+        let (red, green, blue) = blend.mix_precise(
+            (init.red, init.green, init.blue),
+            (target.red, target.green, target.blue),
+            t,
+        );
+        PreciseState {
+            brightness: lerp_f32(init.brightness as f32, target.brightness as f32, t),
+            red,
+            green,
+            blue,
+        }
+    }
+
     #[cfg(test)]
     fn almost_equal(s1: &Self, s2: &Self) -> bool {
         let Self {
@@ -110,25 +266,383 @@ impl LEDState {
     }
 }
 
-pub struct Frames {
-    state: LEDState,
+/// An LED state with fractional channels, kept while rendering so that slow
+/// fades don't lose precision before the frame is written out
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct PreciseState {
+    pub brightness: f32,
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+}
+
+impl From<&LEDState> for PreciseState {
+    fn from(led: &LEDState) -> Self {
+        Self {
+            brightness: led.brightness as f32,
+            red: led.red as f32,
+            green: led.green as f32,
+            blue: led.blue as f32,
+        }
+    }
+}
+
+impl From<&PreciseState> for LEDState {
+    /// Round to the nearest state, with no transition time
+    fn from(state: &PreciseState) -> Self {
+        let channel = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+        LEDState::new(
+            channel(state.brightness),
+            channel(state.red),
+            channel(state.green),
+            channel(state.blue),
+            0.0,
+        )
+    }
+}
+
+/// How LED states are turned into the global brightness and PWM values sent
+/// to the strip
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Encoding {
+    /// Brightness and colour are sent as given
+    #[default]
+    Standard,
+    /// Brightness and colour are multiplied together into a single intensity
+    /// per channel, which is then split back up using the lowest global
+    /// brightness that can show it. Dim colours get the full PWM range rather
+    /// than a handful of values near zero
+    Hdr,
+}
+
+/// Split the combined intensity of `brightness` (out of 31) and each colour
+/// channel (out of 255) into the lowest global brightness that can reach the
+/// brightest channel, and the PWM values that give the same intensity with it
+fn hdr_split(brightness: f32, red: f32, green: f32, blue: f32) -> (f32, f32, f32, f32) {
+    let max_brightness = MAX_BRIGHTNESS as f32;
+    let scale = brightness.clamp(0.0, max_brightness) / max_brightness;
+    let intensity = |c: f32| scale * c.clamp(0.0, 255.0) / 255.0;
+    let (red, green, blue) = (intensity(red), intensity(green), intensity(blue));
+    let brightest = red.max(green).max(blue);
+    if brightest <= 0.0 {
+        return (0.0, 0.0, 0.0, 0.0);
+    }
+    let global = (brightest * max_brightness)
+        .ceil()
+        .clamp(1.0, max_brightness);
+    let pwm = |i: f32| (i * max_brightness / global * 255.0).min(255.0);
+    (global, pwm(red), pwm(green), pwm(blue))
+}
+
+/// Round `value` to the nearest whole number, carrying whatever was lost over
+/// to the next frame in `error`. Averaged over several frames, the output
+/// then matches `value` far more closely than rounding alone could
+fn dither(value: f32, error: &mut f32, max: u8) -> u8 {
+    let wanted = value + *error;
+    let output = wanted.round().clamp(0.0, max as f32);
+    *error = wanted - output;
+    output as u8
+}
+
+/// What a single step of a job transitions the strip towards
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(from = "SavedTarget", into = "SavedTarget")]
+pub enum Target {
+    /// Every LED transitions to the same state
+    All(LEDState),
+    /// Each LED transitions to its own state. LEDs past the end of the list
+    /// are left alone
+    Pixels(Vec<LEDState>),
+    /// Only the LED at the given index transitions, the rest are left alone
+    Pixel(u16, LEDState),
+}
+
+/// `Target` as it is saved. The TOML serialiser can only write enum variants
+/// holding a single value, so save them as tables tagged with the variant
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum SavedTarget {
+    All { led: LEDState },
+    Pixels { leds: Vec<LEDState> },
+    Pixel { index: u16, led: LEDState },
+}
+
+impl From<SavedTarget> for Target {
+    fn from(saved: SavedTarget) -> Self {
+        match saved {
+            SavedTarget::All { led } => Self::All(led),
+            SavedTarget::Pixels { leds } => Self::Pixels(leds),
+            SavedTarget::Pixel { index, led } => Self::Pixel(index, led),
+        }
+    }
+}
+
+impl From<Target> for SavedTarget {
+    fn from(target: Target) -> Self {
+        match target {
+            Target::All(led) => Self::All { led },
+            Target::Pixels(leds) => Self::Pixels { leds },
+            Target::Pixel(index, led) => Self::Pixel { index, led },
+        }
+    }
+}
+
+impl From<LEDState> for Target {
+    fn from(led: LEDState) -> Self {
+        Self::All(led)
+    }
+}
+
+/// A single step of a job: where to go and how to get there
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Step {
+    pub target: Target,
+    pub easing: Easing,
+    pub blend: BlendSpace,
+}
+
+impl Step {
+    /// How long the step takes to play, in seconds
+    pub fn duration(&self) -> f32 {
+        match &self.target {
+            Target::All(led) | Target::Pixel(_, led) => led.time,
+            Target::Pixels(leds) => leds.iter().map(|l| l.time).fold(0.0, f32::max),
+        }
+    }
+}
+
+impl From<Target> for Step {
+    fn from(target: Target) -> Self {
+        Self {
+            target,
+            easing: Easing::default(),
+            blend: BlendSpace::default(),
+        }
+    }
+}
+
+impl From<LEDState> for Step {
+    fn from(led: LEDState) -> Self {
+        Target::from(led).into()
+    }
+}
+
+/// The byte stream for the whole strip and where to send it. Shared between
+/// every `Frames` driving part of the same strip
+struct Strip {
     buffer: Vec<u8>,
+    output: Box<dyn OutputBackend>,
+    /// What the strip is known to be showing, if anything
+    written: Option<Vec<u8>>,
+    /// Which segment is playing a job on each LED, or 0 if none is. Nothing
+    /// else writes to a claimed LED, so jobs on overlapping `Frames` don't
+    /// fight over it
+    claims: Vec<usize>,
+    /// How many segments have been created, to number the next one
+    segments: usize,
+    /// Scales the output of every LED, from 0 to 1, whichever `Frames` set
+    /// it. Applied to the gamma corrected channels, so that dimming is even
+    /// across the range
+    master_brightness: f32,
+    /// How each LED was last set, gamma corrected but not yet encoded, so
+    /// that changing the master brightness can encode them all again
+    levels: Vec<(Encoding, PreciseState)>,
+}
+
+impl Strip {
+    /// Set `pixel` to the gamma corrected `level`, unless a segment other
+    /// than `owner` has claimed it
+    fn set_level(
+        &mut self,
+        owner: usize,
+        pixel: usize,
+        encoding: Encoding,
+        level: PreciseState,
+        error: &mut PreciseState,
+    ) {
+        // a segment that hasn't yet switched over after the strip shrank can
+        // be left past the end
+        match self.claims.get(pixel) {
+            Some(&claim) if claim == 0 || claim == owner => (),
+            _ => return,
+        }
+        self.levels[pixel] = (encoding, level);
+        let encoded = Frames::encode(encoding, self.master_brightness, &level, error);
+        self.set_led_frame(pixel, &encoded);
+    }
+
+    fn set_led_frame(&mut self, pixel: usize, encoded: &LEDState) {
+        let index = (pixel + 1) * 4;
+        self.buffer[index..index + 4].copy_from_slice(&Frames::get_led_frame(encoded));
+    }
+
+    /// Scale every LED by `brightness` from now on, including what they
+    /// already show
+    fn set_master_brightness(&mut self, brightness: f32) {
+        self.master_brightness = brightness;
+        for pixel in 0..self.levels.len() {
+            let (encoding, level) = self.levels[pixel];
+            let encoded =
+                Frames::encode(encoding, brightness, &level, &mut PreciseState::default());
+            self.set_led_frame(pixel, &encoded);
+        }
+    }
+
+    /// Send the buffer to the output, unless it is what the strip already
+    /// shows
+    fn write(&mut self) -> Result<(), OutputError> {
+        if self.written.as_ref() == Some(&self.buffer) {
+            return Ok(());
+        }
+        let result = self.output.write(&self.buffer);
+        // a dropped write leaves the strip showing whatever it was before
+        if result.is_ok() && !self.output.is_down() {
+            self.written = Some(self.buffer.clone());
+        } else {
+            self.written = None;
+        }
+        result
+    }
+}
+
+/// Lock a strip shared between segments. A worker panicking while holding
+/// it leaves at worst a half written frame, so carry on anyway
+fn lock(strip: &Mutex<Strip>) -> MutexGuard<'_, Strip> {
+    strip.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub struct Frames {
+    state: Vec<LEDState>,
+    strip: Arc<Mutex<Strip>>,
+    /// Numbers each segment of the strip from 1, with 0 for the whole strip
+    id: usize,
+    offset: u16,
     num_leds: u16,
-    clock_rate: u32,
+    gamma: Gamma,
+    encoding: Encoding,
+    dither_error: Vec<PreciseState>,
+    /// How fast steps play, as a multiple of their normal speed
+    speed: f32,
+    /// Holding the current frame until resumed, kept between steps so that
+    /// the next one doesn't start playing
+    paused: bool,
     pub sleep_duration: time::Duration,
 }
 
 impl Frames {
-    pub fn new(num_leds: u16, clock_rate: u32, sleep_duration_millis: u64) -> Self {
-        Self {
-            state: LEDState::new(0, 0, 0, 0, 0.0),
+    pub fn new(num_leds: u16, sleep_duration_millis: u64, output: Box<dyn OutputBackend>) -> Self {
+        let strip = Strip {
             buffer: Self::initialise_frames(&num_leds),
+            output,
+            written: None,
+            claims: vec![0; num_leds.into()],
+            segments: 0,
+            master_brightness: 1.0,
+            levels: vec![(Encoding::default(), PreciseState::default()); num_leds.into()],
+        };
+        Self {
+            state: vec![LEDState::new(0, 0, 0, 0, 0.0); num_leds.into()],
+            strip: Arc::new(Mutex::new(strip)),
+            id: 0,
+            offset: 0,
             num_leds,
-            clock_rate,
+            gamma: Gamma::default(),
+            encoding: Encoding::default(),
+            dither_error: vec![PreciseState::default(); num_leds.into()],
+            speed: 1.0,
+            paused: false,
             sleep_duration: time::Duration::from_millis(sleep_duration_millis),
         }
     }
 
+    /// Create a `Frames` that drives `num_leds` LEDs of the same strip,
+    /// starting at `start`. Both share the same output, so each write sends
+    /// whatever every segment last set. While the segment has claimed its
+    /// LEDs, nothing else can set them.
+    ///
+    /// Panics if the segment doesn't fit inside this one.
+    pub fn segment(&self, start: u16, num_leds: u16) -> Self {
+        assert!(
+            start as u32 + num_leds as u32 <= self.num_leds as u32,
+            "segment does not fit in the strip"
+        );
+        let id = {
+            let mut strip = lock(&self.strip);
+            strip.segments += 1;
+            strip.segments
+        };
+        Self {
+            state: self.state[start.into()..(start + num_leds).into()].to_vec(),
+            strip: Arc::clone(&self.strip),
+            id,
+            offset: self.offset + start,
+            num_leds,
+            gamma: self.gamma.clone(),
+            encoding: self.encoding,
+            dither_error: vec![PreciseState::default(); num_leds.into()],
+            speed: self.speed,
+            paused: false,
+            sleep_duration: self.sleep_duration,
+        }
+    }
+
+    /// Create another `Frames` for the whole of the same strip, with the same
+    /// settings, starting with every LED off. Segments created from it share
+    /// the strip with those created from this one
+    pub fn whole_strip(&self) -> Self {
+        let num_leds = lock(&self.strip).claims.len() as u16;
+        Self {
+            state: vec![LEDState::new(0, 0, 0, 0, 0.0); num_leds.into()],
+            strip: Arc::clone(&self.strip),
+            id: 0,
+            offset: 0,
+            num_leds,
+            gamma: self.gamma.clone(),
+            encoding: self.encoding,
+            dither_error: vec![PreciseState::default(); num_leds.into()],
+            speed: 1.0,
+            paused: false,
+            sleep_duration: self.sleep_duration,
+        }
+    }
+
+    /// Resize the strip to `num_leds` and send it to `output` from now on,
+    /// keeping what the LEDs still on it show. Every `Frames` on the strip
+    /// carries on with its old size until switched over to one made from
+    /// `whole_strip`
+    pub fn reconnect(&self, num_leds: u16, output: Box<dyn OutputBackend>) {
+        let mut strip = lock(&self.strip);
+        let kept = strip.claims.len().min(num_leds.into());
+        let mut buffer = Self::initialise_frames(&num_leds);
+        buffer[4..4 + kept * 4].copy_from_slice(&strip.buffer[4..4 + kept * 4]);
+        strip.buffer = buffer;
+        strip.output = output;
+        strip.written = None;
+        strip.claims.resize(num_leds.into(), 0);
+        strip.levels.resize(
+            num_leds.into(),
+            (Encoding::default(), PreciseState::default()),
+        );
+    }
+
+    /// Carry on from here with `new`, made for the same part of the strip
+    /// after a reload: the speed, pause and claim carry over, and `new` takes
+    /// over the state showing
+    pub fn switch_to(&mut self, mut new: Frames) -> Result<(), OutputError> {
+        new.speed = self.speed;
+        new.paused = self.paused;
+        // already the same unless `new` is for another strip
+        new.set_master_brightness(self.master_brightness());
+        let claimed = self.claimed();
+        self.release();
+        let state = std::mem::take(&mut self.state);
+        *self = new;
+        if claimed {
+            self.claim();
+        }
+        self.take_over(&state)
+    }
+
     fn get_start_frame() -> [u8; 4] {
         [0; 4]
     }
@@ -152,47 +666,336 @@ impl Frames {
         // in the number of LEDs in the strip.
         //
         // Using u32::MAX means we can only address a 64 LED strip
-        vec![0; Self::get_end_frame_count(num_leds).into()]
+        vec![0xff; Self::get_end_frame_count(num_leds).into()]
+    }
+
+    /// Set the gamma correction applied to every LED on the way out. Segments
+    /// created afterwards inherit it
+    pub fn set_gamma(&mut self, gamma: Gamma) {
+        self.gamma = gamma;
+    }
+
+    /// Set how LED states are encoded on the way out. Segments created
+    /// afterwards inherit it
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Scale the output of every LED on the strip by `brightness`, from 0 to
+    /// 1, on top of the brightness of each state. Shared with every segment of
+    /// the strip, and takes effect from the next write
+    pub fn set_master_brightness(&mut self, brightness: f32) {
+        lock(&self.strip).set_master_brightness(brightness.clamp(0.0, 1.0));
+    }
+
+    pub fn master_brightness(&self) -> f32 {
+        lock(&self.strip).master_brightness
+    }
+
+    /// Play steps at `speed` times their normal speed, kept between
+    /// `MIN_SPEED` and `MAX_SPEED`. Takes effect straight away, including for
+    /// a step part way through. NaN leaves the speed as it is
+    pub fn set_speed(&mut self, speed: f32) {
+        if !speed.is_nan() {
+            self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Hold the current frame, including across steps, until resumed
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn num_leds(&self) -> u16 {
+        self.num_leds
+    }
+
+    /// Keep anything else from setting this segment's LEDs until released,
+    /// so that a job playing on it shows over one on the whole strip. The
+    /// whole strip never claims its LEDs
+    pub fn claim(&mut self) {
+        if self.id == 0 {
+            return;
+        }
+        let mut strip = lock(&self.strip);
+        let start = (self.offset as usize).min(strip.claims.len());
+        let end = (start + self.num_leds as usize).min(strip.claims.len());
+        strip.claims[start..end].fill(self.id);
+    }
+
+    /// Let the rest of the strip set this segment's LEDs again
+    pub fn release(&mut self) {
+        for claim in &mut lock(&self.strip).claims {
+            if *claim == self.id {
+                *claim = 0;
+            }
+        }
+    }
+
+    fn claimed(&self) -> bool {
+        self.id != 0 && lock(&self.strip).claims.contains(&self.id)
+    }
+
+    /// The state each LED was left in at the end of the last step, or where
+    /// it had got to if the step was interrupted
+    pub fn state(&self) -> &[LEDState] {
+        &self.state
+    }
+
+    /// Carry on from the state another `Frames` for the same LEDs left them
+    /// in, showing it straight away. LEDs it didn't cover are left off
+    pub fn take_over(&mut self, state: &[LEDState]) -> Result<(), OutputError> {
+        for (led, previous) in self.state.iter_mut().zip(state) {
+            *led = *previous;
+        }
+        self.refresh()
+    }
+
+    /// Show the current state again, after changing how it is shown
+    pub fn refresh(&mut self) -> Result<(), OutputError> {
+        let state = self.state.clone();
+        self.set_pixel_frames(&state);
+        self.output_frames()
     }
 
     pub fn set_led_frames(&mut self, led_state: &LEDState) {
-        for i in 0..(self.num_leds as usize) {
-            let leds = Self::get_led_frame(led_state);
-            let index = (i + 1) * 4;
-            for (j, led) in leds.iter().enumerate() {
-                self.buffer[index + j] = *led;
+        self.set_pixel_frames(&vec![*led_state; self.num_leds.into()]);
+    }
+
+    /// Set LEDs to fractional states, dithering over successive frames to
+    /// show the fractional part
+    pub fn set_precise_frames(&mut self, led_states: &[PreciseState]) {
+        let mut strip = lock(&self.strip);
+        for (i, (led_state, error)) in led_states
+            .iter()
+            .zip(self.dither_error.iter_mut())
+            .enumerate()
+        {
+            strip.set_level(
+                self.id,
+                self.offset as usize + i,
+                self.encoding,
+                Self::correct(&self.gamma, led_state),
+                error,
+            );
+        }
+    }
+
+    pub fn set_pixel_frames(&mut self, led_states: &[LEDState]) {
+        let mut strip = lock(&self.strip);
+        for (i, led_state) in led_states.iter().enumerate().take(self.num_leds.into()) {
+            // exact states don't need dithering, so start afresh next time
+            self.dither_error[i] = PreciseState::default();
+            strip.set_level(
+                self.id,
+                self.offset as usize + i,
+                self.encoding,
+                Self::correct(&self.gamma, &led_state.into()),
+                &mut PreciseState::default(),
+            );
+        }
+    }
+
+    /// Apply gamma correction to a logical LED state
+    fn correct(gamma: &Gamma, led_state: &PreciseState) -> PreciseState {
+        let (red, green, blue) =
+            gamma.correct_precise((led_state.red, led_state.green, led_state.blue));
+        PreciseState {
+            red,
+            green,
+            blue,
+            ..*led_state
+        }
+    }
+
+    /// Turn a gamma corrected LED state into the brightness and PWM values to
+    /// send, scaled by `master_brightness`. Scaling comes before dithering so
+    /// that dim colours keep their precision, and before the HDR split so
+    /// that it lowers the global brightness rather than the PWM range
+    fn encode(
+        encoding: Encoding,
+        master_brightness: f32,
+        level: &PreciseState,
+        error: &mut PreciseState,
+    ) -> LEDState {
+        let (red, green, blue) = (
+            level.red * master_brightness,
+            level.green * master_brightness,
+            level.blue * master_brightness,
+        );
+        let (brightness, red, green, blue) = match encoding {
+            Encoding::Standard => (level.brightness, red, green, blue),
+            Encoding::Hdr => hdr_split(level.brightness, red, green, blue),
+        };
+        LEDState {
+            brightness: dither(brightness, &mut error.brightness, MAX_BRIGHTNESS),
+            red: dither(red, &mut error.red, u8::MAX),
+            green: dither(green, &mut error.green, u8::MAX),
+            blue: dither(blue, &mut error.blue, u8::MAX),
+            time: 0.0,
+        }
+    }
+
+    /// Work out the final state of every LED for the given target. LEDs not
+    /// covered by the target keep their current state with no transition time
+    fn resolve_target(&self, target: &Target) -> Vec<LEDState> {
+        let mut targets: Vec<LEDState> = self
+            .state
+            .iter()
+            .map(|s| LEDState { time: 0.0, ..*s })
+            .collect();
+        match target {
+            Target::All(led) => targets.fill(led.cap_brightness()),
+            Target::Pixels(leds) => {
+                for (t, led) in targets.iter_mut().zip(leds) {
+                    *t = led.cap_brightness();
+                }
+            }
+            Target::Pixel(index, led) => {
+                if let Some(t) = targets.get_mut(*index as usize) {
+                    *t = led.cap_brightness();
+                }
             }
         }
+        targets
+    }
+
+    /// Whether every LED is already in its target state
+    fn holds(&self, targets: &[LEDState]) -> bool {
+        self.state.iter().zip(targets).all(|(current, target)| {
+            LEDState {
+                time: 0.0,
+                ..*current
+            } == LEDState {
+                time: 0.0,
+                ..*target
+            }
+        })
     }
 
     fn get_end_frame_count(num_leds: &u16) -> u16 {
         ((num_leds / 64) + 1) * 4
     }
 
+    /// Play `step`, waiting on `controls` between frames for anything that
+    /// changes its course
     pub fn transition(
         &mut self,
-        target: &LEDState,
-        interrupt: &mpsc::Receiver<bool>,
-    ) -> Interrupted<(), rppal::spi::Error> {
-        let start_time = time::Instant::now();
-        let capped_target = target.cap_brightness();
-        while start_time.elapsed().as_secs_f32() < capped_target.time {
-            let delta_time: f32 = start_time.elapsed().as_secs_f32();
-            self.set_led_frames(&LEDState::lerp(&self.state, &capped_target, delta_time));
-            if let Err(e) = self.output_frames() {
-                return Interrupted::No(Err(e));
+        step: &Step,
+        controls: &mut impl Controls,
+    ) -> Interrupted<(), OutputError> {
+        let mut targets = self.resolve_target(&step.target);
+        let mut duration = targets.iter().map(|t| t.time).fold(0.0, f32::max);
+        // if nothing changes then rather than working out the same frame over
+        // and over, just wait for the step to end
+        let mut holding = self.holds(&targets);
+        let mut clock = StepClock::new(self.speed, !self.paused);
+        let mut current: Option<Vec<PreciseState>> = None;
+        loop {
+            let elapsed = clock.elapsed();
+            // a step paused before it got going waits to be resumed, however
+            // short it is
+            if elapsed >= duration && (clock.running || elapsed > 0.0) {
+                break;
+            }
+            // while paused the frame already showing stays as it is
+            if clock.running {
+                if holding {
+                    self.set_pixel_frames(&targets);
+                } else {
+                    let frame: Vec<PreciseState> = self
+                        .state
+                        .iter()
+                        .zip(&targets)
+                        .map(|(init, target)| {
+                            LEDState::interpolate_precise(
+                                init,
+                                target,
+                                elapsed,
+                                &step.easing,
+                                &step.blend,
+                            )
+                        })
+                        .collect();
+                    self.set_precise_frames(&frame);
+                    current = Some(frame);
+                }
+                if let Err(e) = self.output_frames() {
+                    return Interrupted::No(Err(e));
+                }
+            }
+            let timeout = if !clock.running {
+                None
+            } else if holding {
+                // a step too long to wait for in one go might as well never
+                // end, so wait on the controls alone
+                time::Duration::try_from_secs_f32((duration - elapsed) / clock.speed).ok()
+            } else {
+                Some(self.sleep_duration)
+            };
+            let control = controls.wait(timeout);
+            if let Some(new) = controls.reloaded() {
+                // carry on from what is showing, playing what is left of the
+                // step on the new frames
+                if let Some(current) = current.take() {
+                    self.state = current.iter().map(LEDState::from).collect();
+                }
+                if let Err(e) = self.switch_to(new) {
+                    return Interrupted::No(Err(e));
+                }
+                let elapsed = clock.elapsed();
+                clock.restart();
+                targets = self.resolve_target(&step.target);
+                for target in &mut targets {
+                    target.time = (target.time - elapsed).max(0.0);
+                }
+                duration = targets.iter().map(|t| t.time).fold(0.0, f32::max);
+                holding = self.holds(&targets);
             }
-            match interrupt.try_recv() {
-                Ok(_) => return Interrupted::Yes,
-                Err(mpsc::TryRecvError::Empty) => (),
-                Err(mpsc::TryRecvError::Disconnected) => panic!("Thread disconnected!"),
+            match control {
+                None => (),
+                Some(Control::Interrupt) => {
+                    // whatever comes next should start from what is actually
+                    // showing rather than snapping back to the start
+                    if let Some(current) = current {
+                        self.state = current.iter().map(LEDState::from).collect();
+                    }
+                    return Interrupted::Yes;
+                }
+                Some(Control::Pause) => {
+                    self.paused = true;
+                    clock.set_running(false);
+                }
+                Some(Control::Resume) => {
+                    self.paused = false;
+                    clock.set_running(true);
+                }
+                Some(Control::MasterBrightness(brightness)) => {
+                    self.set_master_brightness(brightness);
+                    if let Err(e) = self.output_frames() {
+                        return Interrupted::No(Err(e));
+                    }
+                }
+                Some(Control::Speed(speed)) => {
+                    self.set_speed(speed);
+                    clock.set_speed(self.speed);
+                }
             }
-            thread::sleep(self.sleep_duration);
         }
         // make sure we actually achieved the final state, in case of rounding
         // errors in the lerp
-        self.set_led_frames(&capped_target);
-        self.state = capped_target;
+        self.set_pixel_frames(&targets);
+        // with the full transition times, even if the step was cut short by
+        // switching over
+        self.state = self.resolve_target(&step.target);
         Interrupted::No(self.output_frames())
     }
 
@@ -205,16 +1008,26 @@ impl Frames {
         frames
     }
 
-    pub fn output_frames(&self) -> Result<(), rppal::spi::Error> {
-        let mut spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, self.clock_rate, Mode::Mode0).unwrap();
-        spi.write(&self.buffer)?;
-        Ok(())
+    pub fn output_frames(&mut self) -> Result<(), OutputError> {
+        lock(&self.strip).write()
+    }
+
+    /// Whether the strip's output has failed and not yet recovered
+    pub fn output_down(&self) -> bool {
+        lock(&self.strip).output.is_down()
+    }
+
+    /// How long until the output is worth trying again, if it is down and
+    /// knows
+    pub fn retry_in(&self) -> Option<time::Duration> {
+        lock(&self.strip).output.retry_in()
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::output::RecordingBackend;
 
     #[test]
     fn test_maximum_brightness_white() {
@@ -360,51 +1173,561 @@ mod test {
 
     #[test]
     fn test_red_output_for_2_seconds() {
-        let mut frames = Frames::new(TESTING_NUM_LEDS, 15_000_000, 5);
+        let mut frames = Frames::new(TESTING_NUM_LEDS, 5, Box::new(RecordingBackend::new()));
         let target: LEDState = LEDState::new(255, 255, 0, 0, 0.1);
-        let (_tx, rx) = mpsc::channel();
-        let result = frames.transition(&target, &rx);
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let result = frames.transition(&target.into(), &mut rx);
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_green_output_for_2_seconds() {
-        let mut frames = Frames::new(TESTING_NUM_LEDS, 15_000_000, 5);
+        let mut frames = Frames::new(TESTING_NUM_LEDS, 5, Box::new(RecordingBackend::new()));
         let target: LEDState = LEDState::new(255, 0, 255, 0, 0.1);
-        let (_tx, rx) = mpsc::channel();
-        let result = frames.transition(&target, &rx);
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let result = frames.transition(&target.into(), &mut rx);
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_blue_output_for_2_seconds() {
-        let mut frames = Frames::new(TESTING_NUM_LEDS, 15_000_000, 5);
+        let mut frames = Frames::new(TESTING_NUM_LEDS, 5, Box::new(RecordingBackend::new()));
         let target: LEDState = LEDState::new(255, 0, 0, 255, 0.1);
-        let (_tx, rx) = mpsc::channel();
-        let result = frames.transition(&target, &rx);
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let result = frames.transition(&target.into(), &mut rx);
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_clear_leds_post_testing() {
-        let mut frames = Frames::new(TESTING_NUM_LEDS, 15_000_000, 5);
+        let mut frames = Frames::new(TESTING_NUM_LEDS, 5, Box::new(RecordingBackend::new()));
         let target: LEDState = LEDState::new(0, 0, 0, 0, 0.1);
-        let (_tx, rx) = mpsc::channel();
-        let result = frames.transition(&target, &rx);
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let result = frames.transition(&target.into(), &mut rx);
         assert!(result.is_ok());
     }
 
     #[test]
     fn test_rgb_roundtrip() {
-        let mut frames = Frames::new(TESTING_NUM_LEDS, 15_000_000, 5);
+        let mut frames = Frames::new(TESTING_NUM_LEDS, 5, Box::new(RecordingBackend::new()));
         let red = LEDState::new(255, 255, 0, 0, 1.0);
         let green = LEDState::new(255, 0, 255, 0, 1.0);
         let blue = LEDState::new(255, 0, 0, 255, 1.0);
         let clear = LEDState::new(0, 0, 0, 0, 1.0);
-        let (_tx, rx) = mpsc::channel();
-        assert!(frames.transition(&red, &rx).is_ok());
-        assert!(frames.transition(&green, &rx).is_ok());
-        assert!(frames.transition(&blue, &rx).is_ok());
-        assert!(frames.transition(&clear, &rx).is_ok());
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        assert!(frames.transition(&red.into(), &mut rx).is_ok());
+        assert!(frames.transition(&green.into(), &mut rx).is_ok());
+        assert!(frames.transition(&blue.into(), &mut rx).is_ok());
+        assert!(frames.transition(&clear.into(), &mut rx).is_ok());
+    }
+
+    #[test]
+    fn test_transition_writes_final_state() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(2, 5, Box::new(output.clone()));
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        assert!(frames
+            .transition(&LEDState::new(31, 0x11, 0x22, 0x33, 0.0).into(), &mut rx)
+            .is_ok());
+        assert_eq!(
+            output.writes(),
+            vec![vec![
+                0x00, 0x00, 0x00, 0x00, // start frame
+                0xff, 0x33, 0x22, 0x11, // first LED
+                0xff, 0x33, 0x22, 0x11, // second LED
+                0xff, 0xff, 0xff, 0xff, // end frame
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_static_frames_written_once() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(2, 5, Box::new(output.clone()));
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let red = LEDState::new(31, 255, 0, 0, 0.0);
+        assert!(frames.transition(&red.into(), &mut rx).is_ok());
+        // holding the same colour for a while doesn't write it again
+        let start = time::Instant::now();
+        assert!(frames
+            .transition(&LEDState { time: 0.2, ..red }.into(), &mut rx)
+            .is_ok());
+        assert!(start.elapsed() >= time::Duration::from_millis(200));
+        assert!(frames.output_frames().is_ok());
+        assert_eq!(output.writes().len(), 1);
+    }
+
+    #[test]
+    fn test_holding_can_be_interrupted() {
+        let mut frames = Frames::new(2, 5, Box::new(RecordingBackend::new()));
+        let (tx, mut rx) = mpsc::channel();
+        let clear = LEDState::new(0, 0, 0, 0, 10.0);
+        tx.send(Control::Interrupt).unwrap();
+        assert!(matches!(
+            frames.transition(&clear.into(), &mut rx),
+            Interrupted::Yes
+        ));
+    }
+
+    #[test]
+    fn test_holding_forever_can_be_interrupted() {
+        let mut frames = Frames::new(2, 5, Box::new(RecordingBackend::new()));
+        let (tx, mut rx) = mpsc::channel();
+        for time in [f32::INFINITY, 1e20] {
+            let clear = LEDState::new(0, 0, 0, 0, time);
+            tx.send(Control::Interrupt).unwrap();
+            assert!(matches!(
+                frames.transition(&clear.into(), &mut rx),
+                Interrupted::Yes
+            ));
+        }
+    }
+
+    #[test]
+    fn test_interrupted_transition_keeps_current_state() {
+        let mut frames = Frames::new(2, 5, Box::new(RecordingBackend::new()));
+        let (tx, mut rx) = mpsc::channel();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            tx.send(Control::Interrupt).unwrap();
+        });
+        let red = LEDState::new(31, 255, 0, 0, 1.0);
+        assert!(matches!(
+            frames.transition(&red.into(), &mut rx),
+            Interrupted::Yes
+        ));
+        interrupter.join().unwrap();
+        for led in frames.state() {
+            assert!((64..192).contains(&led.red), "{:?}", led);
+            assert_eq!((led.green, led.blue), (0, 0));
+        }
+    }
+
+    #[test]
+    fn test_paused_transition_holds_elapsed_time() {
+        let mut frames = Frames::new(2, 5, Box::new(RecordingBackend::new()));
+        let (tx, mut rx) = mpsc::channel();
+        let controller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            tx.send(Control::Pause).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(400));
+            tx.send(Control::Resume).unwrap();
+            tx
+        });
+        let start = time::Instant::now();
+        let red = LEDState::new(31, 255, 0, 0, 0.3);
+        assert!(frames.transition(&red.into(), &mut rx).is_ok());
+        assert!(start.elapsed() >= time::Duration::from_millis(700));
+        drop(controller.join().unwrap());
+    }
+
+    /// Hands out a fixed list of controls, one per wait, keeping track of
+    /// how long each wait was for. `frames` are handed over once a wait has
+    /// been paused
+    struct Scripted {
+        controls: Vec<Control>,
+        timeouts: Vec<Option<time::Duration>>,
+        frames: Option<Frames>,
+    }
+
+    impl Controls for Scripted {
+        fn wait(&mut self, timeout: Option<time::Duration>) -> Option<Control> {
+            self.timeouts.push(timeout);
+            if let Some(timeout) = timeout {
+                std::thread::sleep(timeout);
+            }
+            (!self.controls.is_empty()).then(|| self.controls.remove(0))
+        }
+
+        fn reloaded(&mut self) -> Option<Frames> {
+            match self.timeouts.last() {
+                Some(None) => self.frames.take(),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_pause_carries_over_to_next_step() {
+        let mut frames = Frames::new(2, 5, Box::new(RecordingBackend::new()));
+        // paused in the last wait of the first step
+        let mut controls = Scripted {
+            controls: vec![Control::Pause, Control::Resume],
+            timeouts: vec![],
+            frames: None,
+        };
+        let red = LEDState::new(31, 255, 0, 0, 0.001);
+        assert!(frames.transition(&red.into(), &mut controls).is_ok());
+        assert!(frames.paused());
+        assert_eq!(frames.state()[0], LEDState::new(31, 255, 0, 0, 0.001));
+        // the next step doesn't start until resumed
+        let blue = LEDState::new(31, 0, 0, 255, 0.02);
+        assert!(frames.transition(&blue.into(), &mut controls).is_ok());
+        assert_eq!(controls.timeouts[1], None);
+        assert!(!frames.paused());
+    }
+
+    #[test]
+    fn test_switching_over_part_way_through_a_step() {
+        let first = RecordingBackend::new();
+        let second = RecordingBackend::new();
+        let strip = Frames::new(2, 5, Box::new(first.clone()));
+        let mut shelf = strip.whole_strip().segment(1, 1);
+        shelf.claim();
+        // the strip grows and the segment moves while a step is paused
+        strip.reconnect(3, Box::new(second.clone()));
+        let mut controls = Scripted {
+            controls: vec![Control::Pause, Control::Resume],
+            timeouts: vec![],
+            frames: Some(strip.whole_strip().segment(2, 1)),
+        };
+        let red = LEDState::new(31, 255, 0, 0, 0.1);
+        assert!(shelf.transition(&red.into(), &mut controls).is_ok());
+        assert!(controls.frames.is_none());
+        assert_eq!(shelf.state(), &[red]);
+        assert_eq!(lock(&strip.strip).claims, vec![0, 0, shelf.id]);
+        let written = second.last_write().unwrap();
+        assert_eq!(written.len(), 4 + 3 * 4 + 4);
+        assert_eq!(written[12..16], [0xff, 0x00, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn test_speed_scales_step_time() {
+        let mut frames = Frames::new(2, 5, Box::new(RecordingBackend::new()));
+        let (tx, mut rx) = mpsc::channel();
+        frames.set_speed(4.0);
+        let start = time::Instant::now();
+        let red = LEDState::new(31, 255, 0, 0, 0.8);
+        assert!(frames.transition(&red.into(), &mut rx).is_ok());
+        assert!(start.elapsed() < time::Duration::from_millis(400));
+        // slowing down part way through only affects the rest of the step
+        let start = time::Instant::now();
+        let controller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            tx.send(Control::Speed(0.5)).unwrap();
+            tx
+        });
+        let blue = LEDState::new(31, 0, 0, 255, 0.8);
+        assert!(frames.transition(&blue.into(), &mut rx).is_ok());
+        let elapsed = start.elapsed();
+        assert!(elapsed > time::Duration::from_millis(800), "{:?}", elapsed);
+        assert!(elapsed < time::Duration::from_millis(1600), "{:?}", elapsed);
+        assert_eq!(frames.speed(), 0.5);
+        drop(controller.join().unwrap());
+    }
+
+    #[test]
+    fn test_speed_kept_in_range() {
+        let mut frames = Frames::new(2, 5, Box::new(RecordingBackend::new()));
+        frames.set_speed(0.0);
+        assert_eq!(frames.speed(), MIN_SPEED);
+        frames.set_speed(f32::INFINITY);
+        assert_eq!(frames.speed(), MAX_SPEED);
+        frames.set_speed(f32::NAN);
+        assert_eq!(frames.speed(), MAX_SPEED);
+    }
+
+    #[test]
+    fn test_master_brightness_scales_output() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(1, 5, Box::new(output.clone()));
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        frames.set_master_brightness(0.25);
+        assert!(frames
+            .transition(&LEDState::new(31, 200, 100, 0, 0.0).into(), &mut rx)
+            .is_ok());
+        assert_eq!(output.last_write().unwrap()[4..8], [0xff, 0x00, 0x19, 0x32]);
+        frames.set_master_brightness(2.0);
+        assert!(frames.output_frames().is_ok());
+        assert_eq!(output.last_write().unwrap()[4..8], [0xff, 0x00, 0x64, 0xc8]);
+    }
+
+    #[test]
+    fn test_master_brightness_lowers_hdr_global_brightness() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(1, 5, Box::new(output.clone()));
+        frames.set_encoding(Encoding::Hdr);
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        assert!(frames
+            .transition(&LEDState::new(31, 255, 255, 255, 0.0).into(), &mut rx)
+            .is_ok());
+        frames.set_master_brightness(0.5);
+        assert!(frames.output_frames().is_ok());
+        assert_eq!(output.last_write().unwrap()[4..8], [0xf0, 0xf7, 0xf7, 0xf7]);
+    }
+
+    #[test]
+    fn test_master_brightness_dims_every_segment() {
+        let output = RecordingBackend::new();
+        let mut whole = Frames::new(2, 5, Box::new(output.clone()));
+        let mut shelf = whole.segment(1, 1);
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        assert!(whole
+            .transition(&LEDState::new(0, 0, 0, 0, 0.0).into(), &mut rx)
+            .is_ok());
+        assert!(shelf
+            .transition(&LEDState::new(31, 0, 0, 200, 0.0).into(), &mut rx)
+            .is_ok());
+        // dimming from the whole strip leaves the segment's colour alone
+        whole.set_master_brightness(0.5);
+        assert!(whole.output_frames().is_ok());
+        assert_eq!(shelf.master_brightness(), 0.5);
+        assert_eq!(
+            output.last_write().unwrap()[4..12],
+            [0xe0, 0x00, 0x00, 0x00, 0xff, 0x64, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_take_over_state() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(3, 5, Box::new(output.clone()));
+        let red = LEDState::new(31, 255, 0, 0, 0.0);
+        assert!(frames.take_over(&[red, red]).is_ok());
+        assert_eq!(frames.state(), &[red, red, LEDState::new(0, 0, 0, 0, 0.0)]);
+        assert_eq!(
+            output.last_write().unwrap()[4..16],
+            [0xff, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0xff, 0xe0, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_single_pixel_leaves_others_alone() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(3, 5, Box::new(output.clone()));
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let white = LEDState::new(31, 255, 255, 255, 0.0);
+        let red = LEDState::new(31, 255, 0, 0, 0.0);
+        assert!(frames.transition(&white.into(), &mut rx).is_ok());
+        assert!(frames
+            .transition(&Target::Pixel(1, red).into(), &mut rx)
+            .is_ok());
+        assert_eq!(frames.state(), &[white, red, white]);
+        assert_eq!(
+            output.last_write().unwrap()[4..16],
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_pixels_beyond_target_list_left_alone() {
+        let mut frames = Frames::new(3, 5, Box::new(RecordingBackend::new()));
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let blue = LEDState::new(31, 0, 0, 255, 0.0);
+        let green = LEDState::new(31, 0, 255, 0, 0.0);
+        assert!(frames.transition(&blue.into(), &mut rx).is_ok());
+        assert!(frames
+            .transition(&Target::Pixels(vec![green, green]).into(), &mut rx)
+            .is_ok());
+        assert_eq!(frames.state(), &[green, green, blue]);
+    }
+
+    #[test]
+    fn test_lerp_holds_target_after_its_time() {
+        let init = LEDState::new(31, 0, 0, 0, 0.0);
+        let target = LEDState::new(31, 255, 0, 0, 1.0);
+        assert_eq!(LEDState::lerp(&init, &target, 2.0).red, 255);
+        let instant = LEDState::new(31, 0, 255, 0, 0.0);
+        assert_eq!(LEDState::lerp(&init, &instant, 0.5).green, 255);
+    }
+
+    #[test]
+    fn test_segments_share_output() {
+        let output = RecordingBackend::new();
+        let whole = Frames::new(4, 5, Box::new(output.clone()));
+        let mut shelf = whole.segment(0, 2);
+        let mut desk = whole.segment(2, 2);
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let red = LEDState::new(31, 255, 0, 0, 0.0);
+        let blue = LEDState::new(31, 0, 0, 255, 0.0);
+        assert!(shelf.transition(&red.into(), &mut rx).is_ok());
+        assert!(desk.transition(&blue.into(), &mut rx).is_ok());
+        assert_eq!(
+            output.last_write().unwrap(),
+            vec![
+                0x00, 0x00, 0x00, 0x00, // start frame
+                0xff, 0x00, 0x00, 0xff, // shelf
+                0xff, 0x00, 0x00, 0xff, // shelf
+                0xff, 0xff, 0x00, 0x00, // desk
+                0xff, 0xff, 0x00, 0x00, // desk
+                0xff, 0xff, 0xff, 0xff, // end frame
+            ]
+        );
+    }
+
+    #[test]
+    fn test_claimed_segments_show_over_whole_strip() {
+        let output = RecordingBackend::new();
+        let mut whole = Frames::new(4, 5, Box::new(output.clone()));
+        let mut shelf = whole.segment(0, 2);
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        let red = LEDState::new(31, 255, 0, 0, 0.0);
+        let blue = LEDState::new(31, 0, 0, 255, 0.0);
+        shelf.claim();
+        assert!(shelf.transition(&red.into(), &mut rx).is_ok());
+        assert!(whole.transition(&blue.into(), &mut rx).is_ok());
+        assert_eq!(
+            output.last_write().unwrap()[4..20],
+            [
+                0xff, 0x00, 0x00, 0xff, // shelf
+                0xff, 0x00, 0x00, 0xff, // shelf
+                0xff, 0xff, 0x00, 0x00, // rest of the strip
+                0xff, 0xff, 0x00, 0x00, // rest of the strip
+            ]
+        );
+        // once the segment's job ends the whole strip can take it back
+        shelf.release();
+        assert!(whole.refresh().is_ok());
+        assert_eq!(
+            output.last_write().unwrap()[4..20],
+            [0xff, 0xff, 0x00, 0x00].repeat(4)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_segment_must_fit() {
+        let whole = Frames::new(4, 5, Box::new(RecordingBackend::new()));
+        whole.segment(3, 2);
+    }
+
+    #[test]
+    fn test_lerp_eased() {
+        let init = LEDState::new(31, 0, 0, 0, 0.0);
+        let target = LEDState::new(31, 200, 0, 0, 10.0);
+        assert_eq!(
+            LEDState::interpolate(&init, &target, 5.0, &Easing::EaseIn, &BlendSpace::Rgb).red,
+            50
+        );
+        assert_eq!(
+            LEDState::interpolate(&init, &target, 5.0, &Easing::EaseOut, &BlendSpace::Rgb).red,
+            150
+        );
+        assert_eq!(
+            LEDState::interpolate(&init, &target, 9.0, &Easing::Step, &BlendSpace::Rgb).red,
+            0
+        );
+    }
+
+    #[test]
+    fn test_gamma_applied_to_output() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(1, 5, Box::new(output.clone()));
+        frames.set_gamma(Gamma::new(2.0, 1.0, 2.0).unwrap());
+        let (_tx, mut rx) = mpsc::channel::<Control>();
+        assert!(frames
+            .transition(&LEDState::new(31, 128, 128, 255, 0.0).into(), &mut rx)
+            .is_ok());
+        assert_eq!(output.last_write().unwrap()[4..8], [0xff, 0xff, 128, 64]);
+        // the logical state is untouched
+        assert_eq!(frames.state()[0], LEDState::new(31, 128, 128, 255, 0.0));
+    }
+
+    #[test]
+    fn test_interpolate_hsv() {
+        let red = LEDState::new(31, 255, 0, 0, 0.0);
+        let blue = LEDState::new(31, 0, 0, 255, 10.0);
+        let hsv = BlendSpace::Hsv(colour::HueDirection::Shortest);
+        let halfway = LEDState::interpolate(&red, &blue, 5.0, &Easing::Linear, &hsv);
+        assert!(LEDState::almost_equal(
+            &halfway,
+            &LEDState::new(31, 255, 0, 255, 5.0)
+        ));
+    }
+
+    #[test]
+    fn test_interpolate_oklab_blue_to_green() {
+        let init = LEDState::new(31, 0, 0, 255, 0.0);
+        let target = LEDState::new(31, 0, 255, 0, 10.0);
+        let start = LEDState::interpolate(&init, &target, 0.0, &Easing::Linear, &BlendSpace::Oklab);
+        let end = LEDState::interpolate(&init, &target, 10.0, &Easing::Linear, &BlendSpace::Oklab);
+        assert!(LEDState::almost_equal(
+            &start,
+            &LEDState { time: 0.0, ..init }
+        ));
+        assert!(LEDState::almost_equal(
+            &end,
+            &LEDState {
+                time: 10.0,
+                ..target
+            }
+        ));
+        // unlike RGB, the middle of the blend doesn't dip in lightness
+        let middle =
+            LEDState::interpolate(&init, &target, 5.0, &Easing::Linear, &BlendSpace::Oklab);
+        let (lightness, _, _) = colour::rgb_to_oklab((middle.red, middle.green, middle.blue));
+        let (blue_lightness, _, _) = colour::rgb_to_oklab((0, 0, 255));
+        let (green_lightness, _, _) = colour::rgb_to_oklab((0, 255, 0));
+        assert!((lightness - (blue_lightness + green_lightness) / 2.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_dither_averages_to_fractional_value() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(1, 5, Box::new(output.clone()));
+        let state = PreciseState {
+            brightness: 1.5,
+            red: 10.25,
+            green: 0.0,
+            blue: 255.0,
+        };
+        for _ in 0..8 {
+            frames.set_precise_frames(&[state]);
+            assert!(frames.output_frames().is_ok());
+        }
+        let writes = output.writes();
+        let average = |index: usize, mask: u8| {
+            writes.iter().map(|w| (w[index] & mask) as f32).sum::<f32>() / writes.len() as f32
+        };
+        assert_eq!(average(4, 0x1f), 1.5);
+        assert_eq!(average(5, 0xff), 255.0);
+        assert_eq!(average(6, 0xff), 0.0);
+        assert_eq!(average(7, 0xff), 10.25);
+    }
+
+    #[test]
+    fn test_dither_error_bounded() {
+        let mut error = 0.0;
+        for i in 0..1000 {
+            let value = i as f32 / 1000.0 * 255.0;
+            let output = dither(value, &mut error, u8::MAX);
+            assert!((output as f32 - value).abs() <= 1.0);
+            assert!(error.abs() <= 0.5);
+        }
+    }
+
+    #[test]
+    fn test_hdr_split_keeps_intensity() {
+        for (brightness, red, green, blue) in [
+            (31.0, 255.0, 128.0, 0.0),
+            (1.0, 255.0, 0.0, 0.0),
+            (31.0, 8.0, 4.0, 1.0),
+            (0.5, 3.0, 3.0, 3.0),
+        ] {
+            let (global, r, g, b) = hdr_split(brightness, red, green, blue);
+            let before = brightness / 31.0 / 255.0;
+            let after = global / 31.0 / 255.0;
+            assert!((red * before - r * after).abs() < 1e-6);
+            assert!((green * before - g * after).abs() < 1e-6);
+            assert!((blue * before - b * after).abs() < 1e-6);
+        }
+        assert_eq!(hdr_split(0.0, 255.0, 255.0, 255.0), (0.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_hdr_uses_lowest_global_brightness() {
+        let output = RecordingBackend::new();
+        let mut frames = Frames::new(2, 5, Box::new(output.clone()));
+        frames.set_encoding(Encoding::Hdr);
+        frames.set_pixel_frames(&[
+            LEDState::new(31, 255, 255, 255, 0.0),
+            LEDState::new(31, 8, 0, 0, 0.0),
+        ]);
+        assert!(frames.output_frames().is_ok());
+        let write = output.last_write().unwrap();
+        // full brightness still needs the full global brightness
+        assert_eq!(write[4..8], [0xff, 0xff, 0xff, 0xff]);
+        // but a dim red can use the lowest one and get far more PWM steps
+        assert_eq!(write[8..12], [0xe1, 0x00, 0x00, 248]);
     }
 }
@@ -0,0 +1,120 @@
+use crate::colour::{round_rgb, PreciseRgb};
+
+/// Per-channel gamma correction lookup tables, mapping the linear colour
+/// values callers ask for onto the PWM values sent to the LEDs so that equal
+/// steps in colour look like equal steps in brightness
+#[derive(Debug, PartialEq, Clone)]
+pub struct Gamma {
+    red: [f32; 256],
+    green: [f32; 256],
+    blue: [f32; 256],
+}
+
+fn build_table(gamma: f32) -> [f32; 256] {
+    let mut table = [0.0; 256];
+    for (i, v) in table.iter_mut().enumerate() {
+        *v = 255.0 * (i as f32 / 255.0).powf(gamma);
+    }
+    table
+}
+
+/// Look up a fractional value, interpolating between the neighbouring entries
+fn look_up(table: &[f32; 256], value: f32) -> f32 {
+    let value = value.clamp(0.0, 255.0);
+    let below = value.floor() as usize;
+    let above = value.ceil() as usize;
+    let fraction = value - below as f32;
+    table[below] + (table[above] - table[below]) * fraction
+}
+
+impl Gamma {
+    pub fn new(red: f32, green: f32, blue: f32) -> Result<Self, String> {
+        for (name, gamma) in [("red", red), ("green", green), ("blue", blue)] {
+            if !gamma.is_finite() || gamma <= 0.0 {
+                return Err(format!(
+                    "Gamma for {} must be a positive number, got {}",
+                    name, gamma
+                ));
+            }
+        }
+        Ok(Self {
+            red: build_table(red),
+            green: build_table(green),
+            blue: build_table(blue),
+        })
+    }
+
+    /// Tables that leave every value unchanged
+    pub fn identity() -> Self {
+        Self::new(1.0, 1.0, 1.0).unwrap()
+    }
+
+    pub fn correct(&self, red: u8, green: u8, blue: u8) -> (u8, u8, u8) {
+        round_rgb(self.correct_precise((red as f32, green as f32, blue as f32)))
+    }
+
+    /// As `correct`, keeping the fractional part of both the input and the
+    /// output
+    pub fn correct_precise(&self, (red, green, blue): PreciseRgb) -> PreciseRgb {
+        (
+            look_up(&self.red, red),
+            look_up(&self.green, green),
+            look_up(&self.blue, blue),
+        )
+    }
+}
+
+impl Default for Gamma {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_identity_unchanged() {
+        let gamma = Gamma::identity();
+        for i in 0..=255 {
+            assert_eq!(gamma.correct(i, i, i), (i, i, i));
+        }
+    }
+
+    #[test]
+    fn test_per_channel_curves() {
+        let gamma = Gamma::new(2.0, 1.0, 0.5).unwrap();
+        assert_eq!(gamma.correct(0, 0, 0), (0, 0, 0));
+        assert_eq!(gamma.correct(255, 255, 255), (255, 255, 255));
+        // (128 / 255)^2 * 255 = 64.25
+        assert_eq!(gamma.correct(128, 128, 128), (64, 128, 181));
+    }
+
+    #[test]
+    fn test_monotonic() {
+        let gamma = Gamma::new(2.8, 2.2, 2.5).unwrap();
+        for i in 0..255 {
+            let (r1, g1, b1) = gamma.correct(i, i, i);
+            let (r2, g2, b2) = gamma.correct(i + 1, i + 1, i + 1);
+            assert!(r1 <= r2 && g1 <= g2 && b1 <= b2);
+        }
+    }
+
+    #[test]
+    fn test_precise_interpolates_between_entries() {
+        let gamma = Gamma::new(2.0, 1.0, 2.0).unwrap();
+        let (red, green, _) = gamma.correct_precise((10.5, 10.5, 0.0));
+        let (below, _, _) = gamma.correct_precise((10.0, 0.0, 0.0));
+        let (above, _, _) = gamma.correct_precise((11.0, 0.0, 0.0));
+        assert!(below < red && red < above);
+        assert_eq!(green, 10.5);
+    }
+
+    #[test]
+    fn test_invalid_gamma_rejected() {
+        assert!(Gamma::new(0.0, 1.0, 1.0).is_err());
+        assert!(Gamma::new(1.0, -2.2, 1.0).is_err());
+        assert!(Gamma::new(1.0, 1.0, f32::NAN).is_err());
+    }
+}
@@ -1,79 +1,686 @@
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
 use std::sync::mpsc;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use crate::frames::{Frames, LEDState};
+use crate::colour::{BlendSpace, HueDirection};
+use crate::easing::Easing;
+use crate::frames::{LEDState, Step, Target, MAX_SPEED, MIN_SPEED};
 use crate::worker;
+use tokio::sync::{mpsc as async_mpsc, oneshot, watch};
 use zbus::dbus_interface;
 use zbus::fdo::Error;
+use zbus::{Connection, SignalContext};
+
+/// Asks for the config to be read again, getting back whether it worked
+pub type ReloadRequest = oneshot::Sender<Result<(), String>>;
 
 pub struct RustApa102 {
-    pub frames: Frames,
-    pub job_tx: Mutex<mpsc::Sender<worker::Job>>,
-    pub interrupt_tx: Mutex<mpsc::Sender<bool>>,
+    pub num_leds: AtomicU16,
+    pub clock_rate: AtomicU32,
+    pub commands: Mutex<mpsc::Sender<worker::Command>>,
+    /// Kept here as well as by the worker so that reading them back straight
+    /// after setting them gives the new value
+    pub speed: Mutex<f64>,
+    /// Shared by the whole strip and every segment, since it dims all of them
+    pub master_brightness: Arc<Mutex<f64>>,
+    pub queue: Arc<worker::Queue>,
+    /// Whether a repeating job goes back to its first step after a
+    /// notification, rather than the step it had reached
+    pub resume_from_start: AtomicBool,
+    pub reload_tx: async_mpsc::UnboundedSender<ReloadRequest>,
+    pub status: watch::Receiver<worker::Status>,
+}
+
+/// Emit `PropertiesChanged` for the object at `path` whenever its worker's
+/// status changes. Runs until the worker goes away
+pub async fn forward_status_changes(
+    conn: Connection,
+    path: String,
+    mut status: watch::Receiver<worker::Status>,
+) -> zbus::Result<()> {
+    let iface_ref = conn
+        .object_server()
+        .interface::<_, RustApa102>(path)
+        .await?;
+    let mut previous = status.borrow_and_update().clone();
+    while status.changed().await.is_ok() {
+        let current = status.borrow_and_update().clone();
+        let iface = iface_ref.get().await;
+        let ctxt = iface_ref.signal_context();
+        if current.leds != previous.leds {
+            iface.leds_changed(ctxt).await?;
+        }
+        if current.running != previous.running {
+            iface.running_changed(ctxt).await?;
+        }
+        if current.repeating != previous.repeating {
+            iface.repeating_changed(ctxt).await?;
+        }
+        if current.step != previous.step {
+            iface.step_changed(ctxt).await?;
+        }
+        if current.paused != previous.paused {
+            iface.paused_changed(ctxt).await?;
+        }
+        if current.health != previous.health {
+            iface.health_changed(ctxt).await?;
+        }
+        previous = current;
+    }
+    Ok(())
+}
+
+/// Emit the signal matching each event the worker for the object at `path`
+/// sends back. Runs until the worker goes away
+pub async fn forward_events(
+    conn: Connection,
+    path: String,
+    mut events: async_mpsc::UnboundedReceiver<worker::Event>,
+) -> zbus::Result<()> {
+    let ctxt = SignalContext::new(&conn, path)?;
+    while let Some(event) = events.recv().await {
+        match event {
+            worker::Event::JobStarted => RustApa102::job_started(&ctxt).await?,
+            worker::Event::StepReached(index) => RustApa102::step_reached(&ctxt, index).await?,
+            worker::Event::JobFinished => RustApa102::job_finished(&ctxt).await?,
+            worker::Event::JobInterrupted => RustApa102::job_interrupted(&ctxt).await?,
+            worker::Event::OutputError(message) => {
+                RustApa102::output_error(&ctxt, &message).await?
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_blend(blend: &str) -> Result<BlendSpace, Error> {
+    blend
+        .parse()
+        .map_err(|e: crate::colour::ParseBlendSpaceError| Error::InvalidArgs(e.to_string()))
+}
+
+fn leds_from_hex(leds: &[(&str, u8, f32)]) -> Result<Vec<LEDState>, Error> {
+    leds.iter()
+        .map(|(s, b, t)| LEDState::from_hex(s, *b, *t))
+        .collect::<Result<Vec<LEDState>, _>>()
+        .map_err(|e| Error::Failed(e.to_string()))
+}
+
+/// A job that plays more than once needs steps to play, or it would loop
+/// over nothing
+fn check_steps(steps: &[Step], repeats: bool) -> Result<(), Error> {
+    if repeats && steps.is_empty() {
+        Err(Error::InvalidArgs(
+            "A repeating job needs at least one step".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn blended_steps(leds: Vec<LEDState>, blend: BlendSpace) -> Vec<Step> {
+    leds.into_iter()
+        .map(|led| Step {
+            blend,
+            ..led.into()
+        })
+        .collect()
+}
+
+fn eased_steps(steps: &[(LEDState, &str)], blend: BlendSpace) -> Result<Vec<Step>, Error> {
+    steps
+        .iter()
+        .map(|(led, easing)| {
+            Ok(Step {
+                target: Target::All(*led),
+                easing: easing.parse::<Easing>()?,
+                blend,
+            })
+        })
+        .collect::<Result<Vec<Step>, crate::easing::ParseEasingError>>()
+        .map_err(|e| Error::InvalidArgs(e.to_string()))
 }
 
-#[dbus_interface(name = "org.zbus.apa102")]
 impl RustApa102 {
-    fn transition(&mut self, leds: Vec<LEDState>, repeat: bool) -> Result<(), Error> {
-        let job = if repeat {
-            worker::Job::Repeat(leds)
+    fn send_job(&self, steps: Vec<Step>, repeat: bool) -> Result<(), Error> {
+        check_steps(&steps, repeat)?;
+        if repeat {
+            self.send(worker::Job::Repeat(steps))
         } else {
-            worker::Job::OneOff(leds)
-        };
-        self.interrupt_tx
-            .lock()
-            .unwrap()
-            .send(true)
-            .map_err(|e| Error::Failed(e.to_string()))?;
-        self.job_tx
+            self.send(worker::Job::OneOff(steps))
+        }
+    }
+
+    /// Play `steps` once, only returning when they have finished
+    async fn send_job_and_wait(&self, steps: Vec<Step>) -> Result<(), Error> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.send(worker::Job::OneOffAndWait(steps, done_tx))?;
+        match done_rx.await {
+            Ok(worker::Event::JobFinished) => Ok(()),
+            Ok(worker::Event::OutputError(message)) => Err(Error::IOError(message)),
+            Ok(_) => Err(Error::Failed("Interrupted by another job".to_string())),
+            Err(_) => Err(Error::Failed(
+                "The worker stopped before the job finished".to_string(),
+            )),
+        }
+    }
+
+    /// Replace whatever is playing or queued with `scene`
+    pub fn play_scene(&self, scene: worker::Scene) -> Result<(), Error> {
+        self.send(scene.into())
+    }
+
+    /// Replace the current job and anything queued up behind it
+    fn send(&self, job: worker::Job) -> Result<(), Error> {
+        self.queue.clear();
+        self.preempt(job)
+    }
+
+    /// Play `steps` once over the top of the current job
+    fn send_notification(&self, steps: Vec<Step>) -> Result<(), Error> {
+        self.preempt(worker::Job::Notification(
+            steps,
+            self.resume_from_start.load(Ordering::Relaxed),
+        ))
+    }
+
+    fn preempt(&self, job: worker::Job) -> Result<(), Error> {
+        self.check_output()?;
+        self.command(worker::Command::Play(job))
+    }
+
+    fn command(&self, command: worker::Command) -> Result<(), Error> {
+        self.commands
             .lock()
             .unwrap()
-            .send(job)
-            .map_err(|e| Error::Failed(e.to_string()))?;
+            .send(command)
+            .map_err(|_| Error::Failed("The worker has stopped".to_string()))
+    }
+
+    fn enqueue_job(&self, job: worker::Job, policy: worker::QueuePolicy) -> Result<(), Error> {
+        match policy {
+            worker::QueuePolicy::Replace => self.send(job),
+            _ => {
+                self.check_output()?;
+                self.queue.push(job, policy);
+                self.command(worker::Command::Enqueued)
+            }
+        }
+    }
+
+    /// Refuse new jobs while they can't be shown. The worker keeps trying the
+    /// output and accepts jobs again once it works
+    fn check_output(&self) -> Result<(), Error> {
+        let health = &self.status.borrow().health;
+        if health.output_down {
+            Err(Error::IOError(format!(
+                "The LED output is down: {}",
+                health.last_error
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_index(&self, index: u16) -> Result<(), Error> {
+        let num_leds = self.num_leds.load(Ordering::Relaxed);
+        if index < num_leds {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgs(format!(
+                "LED index {} out of range for a strip of {} LEDs",
+                index, num_leds
+            )))
+        }
+    }
+
+    fn check_pixels(&self, leds: &[LEDState]) -> Result<(), Error> {
+        let num_leds = self.num_leds.load(Ordering::Relaxed);
+        if leds.len() <= num_leds.into() {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgs(format!(
+                "{} LED states given for a strip of {} LEDs",
+                leds.len(),
+                num_leds
+            )))
+        }
+    }
+
+    /// Take on the settings from a reloaded config, emitting
+    /// `PropertiesChanged` for any that are different
+    pub async fn update_settings(
+        &self,
+        ctxt: &SignalContext<'_>,
+        num_leds: u16,
+        clock_rate: u32,
+        resume_from_start: bool,
+    ) -> zbus::Result<()> {
+        self.resume_from_start
+            .store(resume_from_start, Ordering::Relaxed);
+        if self.num_leds.swap(num_leds, Ordering::Relaxed) != num_leds {
+            self.num_leds_changed(ctxt).await?;
+        }
+        if self.clock_rate.swap(clock_rate, Ordering::Relaxed) != clock_rate {
+            self.clock_rate_changed(ctxt).await?;
+        }
         Ok(())
     }
+}
 
-    fn transition_hex(&mut self, leds: Vec<(&str, u8, f32)>, repeat: bool) -> Result<(), Error> {
-        let mapped = leds
+#[dbus_interface(name = "org.zbus.apa102")]
+impl RustApa102 {
+    #[dbus_interface(property)]
+    fn num_leds(&self) -> u16 {
+        self.num_leds.load(Ordering::Relaxed)
+    }
+
+    #[dbus_interface(property)]
+    fn clock_rate(&self) -> u32 {
+        self.clock_rate.load(Ordering::Relaxed)
+    }
+
+    /// The state of each LED at the start of the current step, or once the
+    /// last job finished
+    #[dbus_interface(property)]
+    fn leds(&self) -> Vec<(u8, u8, u8, u8, f64)> {
+        self.status
+            .borrow()
+            .leds
             .iter()
-            .map(|(s, b, t)| LEDState::from_hex(s, *b, *t))
-            .collect::<Result<Vec<LEDState>, _>>()
+            .map(LEDState::as_tuple)
+            .collect()
+    }
+
+    #[dbus_interface(property)]
+    fn running(&self) -> bool {
+        self.status.borrow().running
+    }
+
+    #[dbus_interface(property)]
+    fn repeating(&self) -> bool {
+        self.status.borrow().repeating
+    }
+
+    /// Index of the step currently playing within the running job
+    #[dbus_interface(property)]
+    fn step(&self) -> u32 {
+        self.status.borrow().step
+    }
+
+    #[dbus_interface(property)]
+    fn paused(&self) -> bool {
+        self.status.borrow().paused
+    }
+
+    /// How fast jobs play, as a multiple of their normal speed
+    #[dbus_interface(property)]
+    fn speed(&self) -> f64 {
+        *self.speed.lock().unwrap()
+    }
+
+    #[dbus_interface(property)]
+    fn set_speed(&self, speed: f64) -> zbus::Result<()> {
+        // checked after narrowing, since tiny values round down to zero
+        let narrowed = speed as f32;
+        if !(MIN_SPEED..=MAX_SPEED).contains(&narrowed) {
+            return Err(Error::InvalidArgs(format!(
+                "Speed must be from {} to {}, got {:?}",
+                MIN_SPEED, MAX_SPEED, speed
+            ))
+            .into());
+        }
+        self.command(worker::Command::SetSpeed(narrowed))?;
+        *self.speed.lock().unwrap() = speed;
+        Ok(())
+    }
+
+    /// Scales the output of every LED on the strip, from 0 to 1
+    #[dbus_interface(property)]
+    fn master_brightness(&self) -> f64 {
+        *self.master_brightness.lock().unwrap()
+    }
+
+    #[dbus_interface(property)]
+    fn set_master_brightness(&self, brightness: f64) -> zbus::Result<()> {
+        if !(0.0..=1.0).contains(&brightness) {
+            return Err(Error::InvalidArgs(format!(
+                "Master brightness must be from 0 to 1, got {}",
+                brightness
+            ))
+            .into());
+        }
+        self.command(worker::Command::SetBrightness(brightness as f32))?;
+        *self.master_brightness.lock().unwrap() = brightness;
+        Ok(())
+    }
+
+    /// Whether the output is working, how many writes to it have failed, the
+    /// last error, which is empty if there hasn't been one, and how many times
+    /// the worker has been restarted after panicking
+    #[dbus_interface(property)]
+    fn health(&self) -> (bool, u32, String, u32) {
+        let health = self.status.borrow().health.clone();
+        // with the worker gone nothing reaches the output any more
+        if self.status.has_changed().is_err() {
+            return (
+                false,
+                health.errors,
+                "The worker has stopped".to_string(),
+                health.worker_restarts,
+            );
+        }
+        (
+            !health.output_down,
+            health.errors,
+            health.last_error,
+            health.worker_restarts,
+        )
+    }
+
+    /// Read the config file again and apply it to the whole strip and every
+    /// segment. The current jobs carry on from their next step
+    async fn reload(&self) -> Result<(), Error> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.reload_tx
+            .send(done_tx)
             .map_err(|e| Error::Failed(e.to_string()))?;
-        self.transition(mapped, repeat)
+        done_rx
+            .await
+            .map_err(|e| Error::Failed(e.to_string()))?
+            .map_err(Error::Failed)
+    }
+
+    #[dbus_interface(signal)]
+    async fn job_started(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    /// Sent as each step of a job starts, with the index of the step
+    #[dbus_interface(signal)]
+    async fn step_reached(ctxt: &SignalContext<'_>, index: u32) -> zbus::Result<()>;
+
+    /// Sent when a job plays through to the end. Repeating jobs never finish
+    #[dbus_interface(signal)]
+    async fn job_finished(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    /// Sent when a job is cut short by another one
+    #[dbus_interface(signal)]
+    async fn job_interrupted(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn output_error(ctxt: &SignalContext<'_>, message: &str) -> zbus::Result<()>;
+
+    /// Hold the current frame, carrying on from the same point on Resume
+    fn pause(&self) -> Result<(), Error> {
+        self.command(worker::Command::Pause)
+    }
+
+    fn resume(&self) -> Result<(), Error> {
+        self.command(worker::Command::Resume)
+    }
+
+    /// End the current job and drop the queue, leaving the LEDs as they are
+    fn stop(&self) -> Result<(), Error> {
+        self.queue.clear();
+        self.command(worker::Command::Stop)
+    }
+
+    fn transition(&self, leds: Vec<LEDState>, repeat: bool) -> Result<(), Error> {
+        self.send_job(leds.into_iter().map(Step::from).collect(), repeat)
+    }
+
+    /// As Transition, with `mode` saying how often and in which order the
+    /// steps play, e.g. `repeat 3`, `until 1767225600` or `ping-pong repeat`
+    fn transition_ex(&self, leds: Vec<LEDState>, mode: &str) -> Result<(), Error> {
+        let playback = mode
+            .parse::<worker::Playback>()
+            .map_err(|e| Error::InvalidArgs(e.to_string()))?;
+        let steps: Vec<Step> = leds.into_iter().map(Step::from).collect();
+        check_steps(&steps, playback.repeats())?;
+        self.send(worker::Job::Playback(steps, playback))
+    }
+
+    /// As Transition without repeating, returning once the job has finished
+    async fn transition_and_wait(&self, leds: Vec<LEDState>) -> Result<(), Error> {
+        self.send_job_and_wait(leds.into_iter().map(Step::from).collect())
+            .await
+    }
+
+    async fn transition_hex_and_wait(&self, leds: Vec<(&str, u8, f32)>) -> Result<(), Error> {
+        self.transition_and_wait(leds_from_hex(&leds)?).await
+    }
+
+    /// As Transition, with `policy` saying how the job fits in with the
+    /// current one and those already queued
+    fn enqueue(&self, leds: Vec<LEDState>, repeat: bool, policy: &str) -> Result<(), Error> {
+        let policy = policy
+            .parse::<worker::QueuePolicy>()
+            .map_err(|e| Error::InvalidArgs(e.to_string()))?;
+        let steps: Vec<Step> = leds.into_iter().map(Step::from).collect();
+        check_steps(&steps, repeat)?;
+        let job = if repeat {
+            worker::Job::Repeat(steps)
+        } else {
+            worker::Job::OneOff(steps)
+        };
+        self.enqueue_job(job, policy)
+    }
+
+    /// Whether each queued job repeats, how many steps it has and how long
+    /// one play through takes in seconds
+    fn get_queue(&self) -> Vec<(bool, u32, f64)> {
+        self.queue
+            .summary()
+            .into_iter()
+            .map(|(repeat, steps, duration)| (repeat, steps, duration.into()))
+            .collect()
+    }
+
+    /// Drop every queued job, leaving the current one playing
+    fn clear_queue(&self) {
+        self.queue.clear();
+    }
+
+    fn transition_hex(&self, leds: Vec<(&str, u8, f32)>, repeat: bool) -> Result<(), Error> {
+        self.transition(leds_from_hex(&leds)?, repeat)
+    }
+
+    fn transition_blended(
+        &self,
+        leds: Vec<LEDState>,
+        repeat: bool,
+        blend: &str,
+    ) -> Result<(), Error> {
+        self.send_job(blended_steps(leds, parse_blend(blend)?), repeat)
     }
 
-    fn set(&mut self, led: LEDState) -> Result<(), Error> {
+    fn transition_hex_blended(
+        &self,
+        leds: Vec<(&str, u8, f32)>,
+        repeat: bool,
+        blend: &str,
+    ) -> Result<(), Error> {
+        self.transition_blended(leds_from_hex(&leds)?, repeat, blend)
+    }
+
+    fn transition_eased(&self, steps: Vec<(LEDState, &str)>, repeat: bool) -> Result<(), Error> {
+        self.send_job(eased_steps(&steps, BlendSpace::Rgb)?, repeat)
+    }
+
+    fn transition_eased_blended(
+        &self,
+        steps: Vec<(LEDState, &str)>,
+        repeat: bool,
+        blend: &str,
+    ) -> Result<(), Error> {
+        self.send_job(eased_steps(&steps, parse_blend(blend)?)?, repeat)
+    }
+
+    fn transition_hex_eased(
+        &self,
+        steps: Vec<(&str, u8, f32, &str)>,
+        repeat: bool,
+    ) -> Result<(), Error> {
+        let mapped = steps
+            .iter()
+            .map(|(s, b, t, e)| {
+                LEDState::from_hex(s, *b, *t)
+                    .map(|led| (led, *e))
+                    .map_err(|e| Error::Failed(e.to_string()))
+            })
+            .collect::<Result<Vec<(LEDState, &str)>, _>>()?;
+        self.transition_eased(mapped, repeat)
+    }
+
+    fn transition_pixels(&self, steps: Vec<Vec<LEDState>>, repeat: bool) -> Result<(), Error> {
+        for leds in &steps {
+            self.check_pixels(leds)?;
+        }
+        self.send_job(
+            steps
+                .into_iter()
+                .map(|leds| Target::Pixels(leds).into())
+                .collect(),
+            repeat,
+        )
+    }
+
+    fn set_pixel(&self, index: u16, led: LEDState) -> Result<(), Error> {
+        self.check_index(index)?;
+        self.send_job(vec![Target::Pixel(index, led).into()], false)
+    }
+
+    fn set_pixel_hex(&self, index: u16, hex: &str, brightness: u8, time: f32) -> Result<(), Error> {
+        self.check_index(index)?;
+        let led =
+            LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
+        self.send_job(vec![Target::Pixel(index, led).into()], false)
+    }
+
+    fn set_pixels(&self, leds: Vec<LEDState>) -> Result<(), Error> {
+        self.check_pixels(&leds)?;
+        self.send_job(vec![Target::Pixels(leds).into()], false)
+    }
+
+    fn set_pixels_hex(&self, leds: Vec<(&str, u8, f32)>) -> Result<(), Error> {
+        self.set_pixels(leds_from_hex(&leds)?)
+    }
+
+    fn set(&self, led: LEDState) -> Result<(), Error> {
         self.transition(vec![led], false)
     }
 
-    fn set_hex(&mut self, hex: &str, brightness: u8, time: f32) -> Result<(), Error> {
-        let led = LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
+    fn set_hex(&self, hex: &str, brightness: u8, time: f32) -> Result<(), Error> {
+        let led =
+            LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
         self.transition(vec![led], false)
     }
 
-    fn flash(&mut self, led: LEDState) -> Result<(), Error> {
+    fn flash(&self, led: LEDState) -> Result<(), Error> {
         self.transition(vec![led, LEDState::new(0, 0, 0, 0, led.time)], false)
     }
 
-    fn flash_hex(&mut self, hex: &str, brightness: u8, time: f32) -> Result<(), Error> {
-        let led = LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
+    fn flash_hex(&self, hex: &str, brightness: u8, time: f32) -> Result<(), Error> {
+        let led =
+            LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
         self.transition(vec![led, LEDState::new(0, 0, 0, 0, led.time)], false)
     }
 
-    fn pulse(&mut self, led: LEDState) -> Result<(), Error> {
+    async fn flash_and_wait(&self, led: LEDState) -> Result<(), Error> {
+        self.transition_and_wait(vec![led, LEDState::new(0, 0, 0, 0, led.time)])
+            .await
+    }
+
+    async fn flash_hex_and_wait(&self, hex: &str, brightness: u8, time: f32) -> Result<(), Error> {
+        let led =
+            LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
+        self.flash_and_wait(led).await
+    }
+
+    /// As Transition without repeating, but a repeating job that was playing
+    /// carries on once this has finished
+    fn notify(&self, leds: Vec<LEDState>) -> Result<(), Error> {
+        self.send_notification(leds.into_iter().map(Step::from).collect())
+    }
+
+    fn notify_hex(&self, leds: Vec<(&str, u8, f32)>) -> Result<(), Error> {
+        self.notify(leds_from_hex(&leds)?)
+    }
+
+    fn notify_flash(&self, led: LEDState) -> Result<(), Error> {
+        self.notify(vec![led, LEDState::new(0, 0, 0, 0, led.time)])
+    }
+
+    fn notify_flash_hex(&self, hex: &str, brightness: u8, time: f32) -> Result<(), Error> {
+        let led =
+            LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
+        self.notify_flash(led)
+    }
+
+    fn pulse(&self, led: LEDState) -> Result<(), Error> {
         self.transition(vec![led, LEDState::new(0, 0, 0, 0, led.time)], true)
     }
 
-    fn pulse_hex(&mut self, hex: &str, brightness: u8,time: f32) -> Result<(), Error> {
-        let led = LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
+    fn pulse_hex(&self, hex: &str, brightness: u8, time: f32) -> Result<(), Error> {
+        let led =
+            LEDState::from_hex(hex, brightness, time).map_err(|e| Error::Failed(e.to_string()))?;
         self.transition(vec![led, LEDState::new(0, 0, 0, 0, led.time)], true)
     }
 
-    fn clear(&mut self) -> Result<(), Error> {
+    fn set_hsv(
+        &self,
+        hue: f32,
+        saturation: f32,
+        value: f32,
+        brightness: u8,
+        time: f32,
+    ) -> Result<(), Error> {
+        let led = LEDState::from_hsv(hue, saturation, value, brightness, time);
+        self.send_job(
+            blended_steps(vec![led], BlendSpace::Hsv(HueDirection::Shortest)),
+            false,
+        )
+    }
+
+    fn flash_hsv(
+        &self,
+        hue: f32,
+        saturation: f32,
+        value: f32,
+        brightness: u8,
+        time: f32,
+    ) -> Result<(), Error> {
+        let led = LEDState::from_hsv(hue, saturation, value, brightness, time);
+        self.send_job(
+            blended_steps(
+                vec![led, LEDState::new(0, 0, 0, 0, led.time)],
+                BlendSpace::Hsv(HueDirection::Shortest),
+            ),
+            false,
+        )
+    }
+
+    fn pulse_hsv(
+        &self,
+        hue: f32,
+        saturation: f32,
+        value: f32,
+        brightness: u8,
+        time: f32,
+    ) -> Result<(), Error> {
+        let led = LEDState::from_hsv(hue, saturation, value, brightness, time);
+        self.send_job(
+            blended_steps(
+                vec![led, LEDState::new(0, 0, 0, 0, led.time)],
+                BlendSpace::Hsv(HueDirection::Shortest),
+            ),
+            true,
+        )
+    }
+
+    fn clear(&self) -> Result<(), Error> {
         self.transition(vec![LEDState::new(0, 0, 0, 0, 1.0)], false)
     }
 
-    fn rainbow(&mut self, brightness: u8, time: f32, repeat: bool) -> Result<(), Error> {
+    fn rainbow(&self, brightness: u8, time: f32, repeat: bool) -> Result<(), Error> {
         let v = vec![
             LEDState::from_hex("ff0000", brightness, time).unwrap(), // red
             LEDState::from_hex("ffa500", brightness, time).unwrap(), // orange
@@ -1,3 +1,8 @@
+pub mod colour;
+pub mod easing;
 pub mod frames;
+pub mod gamma;
 pub mod interface;
+pub mod output;
+pub mod persistence;
 pub mod worker;
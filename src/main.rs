@@ -1,27 +1,40 @@
 extern crate xdg;
 use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::future::pending;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32};
 use std::sync::mpsc;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use serde::Deserialize;
-use zbus::ConnectionBuilder;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc as async_mpsc, watch};
+use zbus::{Connection, ConnectionBuilder};
 
-use crate::frames::Frames;
-
-mod frames;
-mod interface;
-mod worker;
+use apa102_dbus::frames::{Encoding, Frames, LEDState};
+use apa102_dbus::gamma::Gamma;
+use apa102_dbus::output::{SpiBackend, SpiConfig};
+use apa102_dbus::{interface, persistence, worker};
 
 const DEFAULT_NUM_LEDS: Option<u16> = Some(1);
 const DEFAULT_CLOCK_RATE: Option<u32> = Some(15_000_000);
+const DEFAULT_SPI_BUS: Option<u8> = Some(0);
+const DEFAULT_SLAVE_SELECT: Option<u8> = Some(0);
+const DEFAULT_SPI_MODE: Option<u8> = Some(0);
+const DEFAULT_BIT_ORDER: &str = "msb-first";
 const DEFAULT_SLEEP_DURATION: Option<u64> = Some(5);
+const DEFAULT_GAMMA: Option<f32> = Some(1.0);
+const DEFAULT_HDR: Option<bool> = Some(false);
+const DEFAULT_RESUME_FROM_START: Option<bool> = Some(false);
+const DEFAULT_STARTUP: Option<Startup> = Some(Startup::Restore);
 
-#[derive(Parser, Debug, Deserialize)]
+#[derive(Parser, Debug, Default, Deserialize)]
 #[command(author, version, about, long_about = None)]
+#[serde(deny_unknown_fields)]
 struct Args {
     /// Number of LEDs in the strip
     #[arg(short, long)]
@@ -29,16 +42,159 @@ struct Args {
     #[arg(short, long)]
     /// Clock rate to use
     clock_rate: Option<u32>,
+    #[arg(long)]
+    /// SPI bus the strip is connected to, as in /dev/spidev<bus>.<slave select>
+    spi_bus: Option<u8>,
+    #[arg(long)]
+    /// SPI slave (chip) select to use, as in /dev/spidev<bus>.<slave select>
+    slave_select: Option<u8>,
+    #[arg(long)]
+    /// SPI mode from 0 to 3
+    spi_mode: Option<u8>,
+    #[arg(long)]
+    /// Order to send the bits of each byte in, msb-first or lsb-first
+    bit_order: Option<String>,
     #[arg(short, long)]
     /// Sleep duration between updates in milliseconds
     sleep_duration: Option<u64>,
+    #[arg(short, long)]
+    /// Gamma correction for all colour channels. 1.0 leaves colours unchanged
+    gamma: Option<f32>,
+    #[arg(long)]
+    /// Gamma correction for the red channel, overriding --gamma
+    gamma_red: Option<f32>,
+    #[arg(long)]
+    /// Gamma correction for the green channel, overriding --gamma
+    gamma_green: Option<f32>,
+    #[arg(long)]
+    /// Gamma correction for the blue channel, overriding --gamma
+    gamma_blue: Option<f32>,
+    #[arg(long)]
+    /// Combine brightness and colour and use the lowest global brightness
+    /// that can show them, for smoother dim colours
+    hdr: Option<bool>,
+    #[arg(long)]
+    /// After a notification, restart the repeating job it interrupted from
+    /// its first step instead of the step it had reached
+    resume_from_start: Option<bool>,
+    #[arg(long, value_enum)]
+    /// What to show on starting up: whatever was playing when last stopped,
+    /// nothing, or the startup scene from the config file
+    startup: Option<Startup>,
+    /// Scene to play on starting up. Only settable from the config file
+    #[arg(skip)]
+    startup_scene: Option<StartupScene>,
+    /// Named segments of the strip. Only settable from the config file
+    #[arg(skip)]
+    segments: Option<Vec<Segment>>,
 }
 
-fn get_args_from_config_file() -> Option<Args> {
-    let base_dir = xdg::BaseDirectories::new().ok()?;
-    let file = base_dir.find_config_file("apa102-dbus/config.toml")?;
-    let contents = fs::read_to_string(file).ok()?;
-    toml::from_str(&contents).ok()
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Segment {
+    name: String,
+    start: u16,
+    length: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Startup {
+    Restore,
+    Clear,
+    Scene,
+}
+
+impl fmt::Display for Startup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Startup::Restore => "restore",
+            Startup::Clear => "clear",
+            Startup::Scene => "scene",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartupScene {
+    steps: Vec<StartupStep>,
+    #[serde(default)]
+    repeat: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartupStep {
+    hex: String,
+    brightness: u8,
+    time: f32,
+}
+
+impl StartupScene {
+    fn to_scene(&self) -> Result<worker::Scene, String> {
+        let steps = self
+            .steps
+            .iter()
+            .map(|s| LEDState::from_hex(&s.hex, s.brightness, s.time).map(Into::into))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("Couldn't read the startup scene: {}", e))?;
+        let repeat = if self.repeat {
+            worker::Repeat::Forever
+        } else {
+            worker::Repeat::Once
+        };
+        Ok(worker::Scene {
+            steps,
+            playback: repeat.into(),
+        })
+    }
+}
+
+fn check_segments(segments: &[Segment], num_leds: u16) -> Result<(), String> {
+    for (i, segment) in segments.iter().enumerate() {
+        // the name becomes part of the segment's D-Bus object path
+        if segment.name.is_empty()
+            || !segment
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!(
+                "Segment name {:?} must only contain ASCII letters, digits and underscores",
+                segment.name
+            ));
+        }
+        if segments[..i].iter().any(|s| s.name == segment.name) {
+            return Err(format!(
+                "Segment name {:?} is used more than once",
+                segment.name
+            ));
+        }
+        if segment.start as u32 + segment.length as u32 > num_leds as u32 {
+            return Err(format!(
+                "Segment {:?} runs past the end of the {} LED strip",
+                segment.name, num_leds
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read the arguments set in `config.toml`. Having no config file is fine,
+/// but one that can't be read or parsed is an error rather than being
+/// ignored
+fn get_args_from_config_file() -> Result<Args, String> {
+    let file = xdg::BaseDirectories::new()
+        .ok()
+        .and_then(|base_dir| base_dir.find_config_file("apa102-dbus/config.toml"));
+    let Some(file) = file else {
+        return Ok(Args::default());
+    };
+    let contents = fs::read_to_string(&file)
+        .map_err(|e| format!("Couldn't read {}: {}", file.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format!("Couldn't parse {}: {}", file.display(), e))
 }
 
 fn work_out_single_arg<'a, T: std::fmt::Display>(
@@ -61,16 +217,35 @@ fn work_out_single_arg<'a, T: std::fmt::Display>(
     }
 }
 
-fn work_out_args() -> (u16, u32, u64) {
+struct Settings {
+    num_leds: u16,
+    clock_rate: u32,
+    spi: SpiConfig,
+    sleep_duration: u64,
+    gamma: Gamma,
+    encoding: Encoding,
+    resume_from_start: bool,
+    startup: Startup,
+    startup_scene: Option<worker::Scene>,
+    segments: Vec<Segment>,
+}
+
+fn work_out_args() -> Result<Settings, String> {
     let cli = Args::parse();
-    let config = match get_args_from_config_file() {
-        Some(x) => x,
-        None => Args { num_leds: None, clock_rate: None, sleep_duration: None },
-    };
+    let config = get_args_from_config_file()?;
     let default = Args {
         num_leds: DEFAULT_NUM_LEDS,
         clock_rate: DEFAULT_CLOCK_RATE,
+        spi_bus: DEFAULT_SPI_BUS,
+        slave_select: DEFAULT_SLAVE_SELECT,
+        spi_mode: DEFAULT_SPI_MODE,
+        bit_order: Some(DEFAULT_BIT_ORDER.to_string()),
         sleep_duration: DEFAULT_SLEEP_DURATION,
+        gamma: DEFAULT_GAMMA,
+        hdr: DEFAULT_HDR,
+        resume_from_start: DEFAULT_RESUME_FROM_START,
+        startup: DEFAULT_STARTUP,
+        ..Default::default()
     };
     let num_leds = work_out_single_arg(
         &cli.num_leds,
@@ -84,6 +259,30 @@ fn work_out_args() -> (u16, u32, u64) {
         &default.clock_rate,
         "Clock rate",
     );
+    let spi = SpiConfig::new(
+        *work_out_single_arg(&cli.spi_bus, &config.spi_bus, &default.spi_bus, "SPI bus").unwrap(),
+        *work_out_single_arg(
+            &cli.slave_select,
+            &config.slave_select,
+            &default.slave_select,
+            "Slave select",
+        )
+        .unwrap(),
+        *work_out_single_arg(
+            &cli.spi_mode,
+            &config.spi_mode,
+            &default.spi_mode,
+            "SPI mode",
+        )
+        .unwrap(),
+        work_out_single_arg(
+            &cli.bit_order,
+            &config.bit_order,
+            &default.bit_order,
+            "Bit order",
+        )
+        .unwrap(),
+    )?;
     let sleep_duration = work_out_single_arg(
         &cli.sleep_duration,
         &config.sleep_duration,
@@ -91,39 +290,310 @@ fn work_out_args() -> (u16, u32, u64) {
         "Sleep duration",
     );
     // ok to unwrap here since the defaults will at least always be Some
-    (
-        *num_leds.unwrap(),
-        *clock_rate.unwrap(),
-        *sleep_duration.unwrap(),
+    let gamma = *work_out_single_arg(&cli.gamma, &config.gamma, &default.gamma, "Gamma").unwrap();
+    let channel_gamma = |cli, config, name| {
+        work_out_single_arg(cli, config, &None, name)
+            .copied()
+            .unwrap_or(gamma)
+    };
+    let gamma = Gamma::new(
+        channel_gamma(&cli.gamma_red, &config.gamma_red, "Red gamma"),
+        channel_gamma(&cli.gamma_green, &config.gamma_green, "Green gamma"),
+        channel_gamma(&cli.gamma_blue, &config.gamma_blue, "Blue gamma"),
+    )?;
+    let encoding = if *work_out_single_arg(&cli.hdr, &config.hdr, &default.hdr, "HDR").unwrap() {
+        Encoding::Hdr
+    } else {
+        Encoding::Standard
+    };
+    let resume_from_start = *work_out_single_arg(
+        &cli.resume_from_start,
+        &config.resume_from_start,
+        &default.resume_from_start,
+        "Resume from start",
     )
+    .unwrap();
+    let startup =
+        *work_out_single_arg(&cli.startup, &config.startup, &default.startup, "Startup").unwrap();
+    let startup_scene = config
+        .startup_scene
+        .as_ref()
+        .map(StartupScene::to_scene)
+        .transpose()?;
+    if startup == Startup::Scene && startup_scene.is_none() {
+        return Err("Startup is set to scene but no startup_scene is configured".to_string());
+    }
+    let segments = config.segments.unwrap_or_default();
+    for segment in &segments {
+        println!(
+            "Segment {} taken from config file starting at {} with length {}",
+            segment.name, segment.start, segment.length
+        );
+    }
+    let num_leds = *num_leds.unwrap();
+    check_segments(&segments, num_leds)?;
+    Ok(Settings {
+        num_leds,
+        clock_rate: *clock_rate.unwrap(),
+        spi,
+        sleep_duration: *sleep_duration.unwrap(),
+        gamma,
+        encoding,
+        resume_from_start,
+        startup,
+        startup_scene,
+        segments,
+    })
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let (num_leds, clock_rate, sleep_duration) = work_out_args();
-    let (job_tx, job_rx) = mpsc::channel();
-    let (interrupt_tx, interrupt_rx) = mpsc::channel();
-    #[allow(unused_must_use)]
-    thread::spawn(move || {
-        worker::update_leds(
-            &mut Frames::new(num_leds, clock_rate, sleep_duration),
-            job_rx,
-            interrupt_rx,
-        );
+/// Where to save the scene for an object, creating the directory if needed
+fn scene_file(name: &str) -> Option<PathBuf> {
+    let base_dir = xdg::BaseDirectories::new().ok()?;
+    match base_dir.place_state_file(format!("apa102-dbus/{}.toml", name)) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            eprintln!("Not saving scenes for {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// The scene to play on starting up. Only the whole strip clears or plays
+/// the startup scene, since that covers the segments as well
+fn starting_scene(
+    settings: &Settings,
+    scene_file: Option<&PathBuf>,
+    whole_strip: bool,
+) -> Option<worker::Scene> {
+    match settings.startup {
+        Startup::Restore => {
+            let file = scene_file?;
+            if !file.exists() {
+                return None;
+            }
+            persistence::load_scene(file)
+                .map_err(|e| eprintln!("Not restoring the last scene: {}", e))
+                .ok()
+        }
+        Startup::Clear if whole_strip => Some(worker::Scene {
+            steps: vec![LEDState::new(0, 0, 0, 0, 1.0).into()],
+            playback: worker::Playback::default(),
+        }),
+        Startup::Scene if whole_strip => settings.startup_scene.clone(),
+        _ => None,
+    }
+}
+
+/// The strip every object plays on, sending to the SPI output
+fn open_strip(settings: &Settings) -> Frames {
+    Frames::new(
+        settings.num_leds,
+        settings.sleep_duration,
+        Box::new(SpiBackend::new(settings.clock_rate, settings.spi)),
+    )
+}
+
+/// Frames for the whole of `strip` followed by one for each segment, all
+/// sharing its buffer and output
+fn build_frames(settings: &Settings, strip: &Frames) -> Vec<Frames> {
+    let mut frames = strip.whole_strip();
+    frames.sleep_duration = Duration::from_millis(settings.sleep_duration);
+    frames.set_gamma(settings.gamma.clone());
+    frames.set_encoding(settings.encoding);
+    let mut all: Vec<Frames> = settings
+        .segments
+        .iter()
+        .map(|segment| frames.segment(segment.start, segment.length))
+        .collect();
+    all.insert(0, frames);
+    all
+}
+
+/// What's needed to reload and shut down a worker
+struct Worker {
+    commands: mpsc::Sender<worker::Command>,
+    thread: thread::JoinHandle<()>,
+}
+
+fn spawn_worker(
+    mut frames: Frames,
+    settings: &Settings,
+    master_brightness: Arc<Mutex<f64>>,
+    reload_tx: async_mpsc::UnboundedSender<interface::ReloadRequest>,
+) -> (
+    interface::RustApa102,
+    async_mpsc::UnboundedReceiver<worker::Event>,
+    Worker,
+) {
+    let num_leds = frames.num_leds();
+    let (command_tx, command_rx) = mpsc::channel();
+    let (status_tx, status_rx) = watch::channel(worker::Status::default());
+    let (event_tx, event_rx) = async_mpsc::unbounded_channel();
+    let queue = Arc::new(worker::Queue::default());
+    let worker_queue = queue.clone();
+    let thread = thread::spawn(move || {
+        worker::update_leds(&mut frames, command_rx, &worker_queue, status_tx, event_tx);
     });
+    let worker = Worker {
+        commands: command_tx.clone(),
+        thread,
+    };
     let inst = interface::RustApa102 {
-        frames: Frames::new(num_leds, clock_rate, sleep_duration),
-        job_tx: Mutex::new(job_tx),
-        interrupt_tx: Mutex::new(interrupt_tx),
+        num_leds: AtomicU16::new(num_leds),
+        clock_rate: AtomicU32::new(settings.clock_rate),
+        commands: Mutex::new(command_tx),
+        speed: Mutex::new(1.0),
+        master_brightness,
+        queue,
+        resume_from_start: AtomicBool::new(settings.resume_from_start),
+        reload_tx,
+        status: status_rx,
+    };
+    (inst, event_rx, worker)
+}
+
+/// Read the config again, resize `strip` and point it at the new output, and
+/// hand the new settings to every worker, which switch over straight away
+async fn reload(
+    conn: &Connection,
+    settings: &mut Settings,
+    strip: &Frames,
+    workers: &[(String, Worker)],
+) -> Result<(), String> {
+    let new = work_out_args()?;
+    let names = |s: &Settings| {
+        s.segments
+            .iter()
+            .map(|segment| segment.name.clone())
+            .collect::<Vec<String>>()
     };
-    let _conn = ConnectionBuilder::session()?
-        .name("org.zbus.apa102")?
-        .serve_at("/org/zbus/apa102", inst)?
-        .build()
-        .await?;
+    if names(&new) != names(settings) {
+        return Err(
+            "Segments can be moved or resized, but adding, removing or renaming them needs a restart"
+                .to_string(),
+        );
+    }
+    strip.reconnect(
+        new.num_leds,
+        Box::new(SpiBackend::new(new.clock_rate, new.spi)),
+    );
+    for ((path, worker), frames) in workers.iter().zip(build_frames(&new, strip)) {
+        let num_leds = frames.num_leds();
+        worker
+            .commands
+            .send(worker::Command::Reload(Box::new(frames)))
+            .map_err(|_| format!("The worker for {} has stopped", path))?;
+        let iface_ref = conn
+            .object_server()
+            .interface::<_, interface::RustApa102>(path.as_str())
+            .await
+            .map_err(|e| e.to_string())?;
+        iface_ref
+            .get()
+            .await
+            .update_settings(
+                iface_ref.signal_context(),
+                num_leds,
+                new.clock_rate,
+                new.resume_from_start,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    *settings = new;
+    Ok(())
+}
 
-    // Wait forever
-    pending::<()>().await;
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut settings = work_out_args()?;
+    // the whole strip comes first so the segments' scenes show over the top
+    let mut objects = vec![("/org/zbus/apa102".to_string(), scene_file("strip"))];
+    // each segment gets its own worker so its jobs run independently of the
+    // rest of the strip
+    for segment in &settings.segments {
+        objects.push((
+            format!("/org/zbus/apa102/segments/{}", segment.name),
+            scene_file(&format!("segments/{}", segment.name)),
+        ));
+    }
+    let (reload_tx, mut reload_rx) = async_mpsc::unbounded_channel();
+    let mut builder = ConnectionBuilder::session()?.name("org.zbus.apa102")?;
+    let mut feedback = vec![];
+    let mut workers = vec![];
+    let master_brightness = Arc::new(Mutex::new(1.0));
+    let strip = open_strip(&settings);
+    for ((path, scene_file), frames) in objects.into_iter().zip(build_frames(&settings, &strip)) {
+        let whole_strip = path == "/org/zbus/apa102";
+        let (inst, events, worker) = spawn_worker(
+            frames,
+            &settings,
+            master_brightness.clone(),
+            reload_tx.clone(),
+        );
+        if let Some(scene) = starting_scene(&settings, scene_file.as_ref(), whole_strip) {
+            inst.play_scene(scene)?;
+        }
+        if let Some(scene_file) = scene_file {
+            tokio::spawn(persistence::save_scenes(inst.status.clone(), scene_file));
+        }
+        feedback.push((path.clone(), inst.status.clone(), events));
+        workers.push((path.clone(), worker));
+        builder = builder.serve_at(path, inst)?;
+    }
+    let conn = builder.build().await?;
+    for (path, status, events) in feedback {
+        let status_conn = conn.clone();
+        let status_path = path.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                interface::forward_status_changes(status_conn, status_path.clone(), status).await
+            {
+                eprintln!(
+                    "Stopped sending property changes for {}: {}",
+                    status_path, e
+                );
+            }
+        });
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            if let Err(e) = interface::forward_events(conn, path.clone(), events).await {
+                eprintln!("Stopped sending signals for {}: {}", path, e);
+            }
+        });
+    }
 
+    // Reload the config whenever asked to, over D-Bus or with SIGHUP, until
+    // told to stop
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        let done = tokio::select! {
+            Some(done) = reload_rx.recv() => Some(done),
+            _ = hangup.recv() => None,
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let result = reload(&conn, &mut settings, &strip, &workers).await;
+        match done {
+            Some(done) => {
+                let _ = done.send(result);
+            }
+            None => {
+                if let Err(e) = result {
+                    eprintln!("Couldn't reload the config: {}", e);
+                }
+            }
+        }
+    }
+
+    // let each worker finish the frame it's on, so the strip isn't left with
+    // half of one
+    for (_, worker) in &workers {
+        let _ = worker.commands.send(worker::Command::Shutdown);
+    }
+    for (_, worker) in workers {
+        let _ = worker.thread.join();
+    }
     Ok(())
 }
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use std::borrow::Cow;
+
+use rppal::spi::{reverse_bits, BitOrder, Bus, Mode, SlaveSelect, Spi};
+
+/// How long to wait before trying to open the SPI device again after the
+/// first failure. Doubles with every failure after that, up to the maximum
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum OutputError {
+    /// The SPI device couldn't be opened or set up
+    Open(rppal::spi::Error),
+    Write(rppal::spi::Error),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open(e) => write!(f, "Couldn't open the SPI device: {}", e),
+            Self::Write(e) => write!(f, "Couldn't write to the SPI device: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Open(e) | Self::Write(e) => Some(e),
+        }
+    }
+}
+
+/// Somewhere to send the raw APA102 byte stream produced by `Frames`
+pub trait OutputBackend: Send {
+    fn write(&mut self, buffer: &[u8]) -> Result<(), OutputError>;
+
+    /// Whether the last write failed and nothing has been written since
+    fn is_down(&self) -> bool {
+        false
+    }
+
+    /// How long until it's worth writing again to find out whether the
+    /// output is back, if it knows
+    fn retry_in(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Which SPI device to use and how to talk to it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SpiConfig {
+    pub bus: Bus,
+    pub slave_select: SlaveSelect,
+    pub mode: Mode,
+    pub bit_order: BitOrder,
+}
+
+impl SpiConfig {
+    /// Takes the bus and slave select numbers as in `/dev/spidev<bus>.<slave
+    /// select>`, the SPI mode from 0 to 3 and a bit order of `msb-first` or
+    /// `lsb-first`
+    pub fn new(bus: u8, slave_select: u8, mode: u8, bit_order: &str) -> Result<Self, String> {
+        let bus = match bus {
+            0 => Bus::Spi0,
+            1 => Bus::Spi1,
+            2 => Bus::Spi2,
+            3 => Bus::Spi3,
+            4 => Bus::Spi4,
+            5 => Bus::Spi5,
+            6 => Bus::Spi6,
+            _ => return Err(format!("SPI bus must be from 0 to 6, got {}", bus)),
+        };
+        let slave_select = match slave_select {
+            0 => SlaveSelect::Ss0,
+            1 => SlaveSelect::Ss1,
+            2 => SlaveSelect::Ss2,
+            3 => SlaveSelect::Ss3,
+            4 => SlaveSelect::Ss4,
+            5 => SlaveSelect::Ss5,
+            6 => SlaveSelect::Ss6,
+            7 => SlaveSelect::Ss7,
+            8 => SlaveSelect::Ss8,
+            9 => SlaveSelect::Ss9,
+            10 => SlaveSelect::Ss10,
+            11 => SlaveSelect::Ss11,
+            12 => SlaveSelect::Ss12,
+            13 => SlaveSelect::Ss13,
+            14 => SlaveSelect::Ss14,
+            15 => SlaveSelect::Ss15,
+            _ => {
+                return Err(format!(
+                    "SPI slave select must be from 0 to 15, got {}",
+                    slave_select
+                ))
+            }
+        };
+        let mode = match mode {
+            0 => Mode::Mode0,
+            1 => Mode::Mode1,
+            2 => Mode::Mode2,
+            3 => Mode::Mode3,
+            _ => return Err(format!("SPI mode must be from 0 to 3, got {}", mode)),
+        };
+        let bit_order = match bit_order.trim().to_ascii_lowercase().as_str() {
+            "msb-first" => BitOrder::MsbFirst,
+            "lsb-first" => BitOrder::LsbFirst,
+            _ => {
+                return Err(format!(
+                    "SPI bit order must be msb-first or lsb-first, got {:?}",
+                    bit_order
+                ))
+            }
+        };
+        Ok(Self {
+            bus,
+            slave_select,
+            mode,
+            bit_order,
+        })
+    }
+}
+
+impl Default for SpiConfig {
+    /// The first chip select on the Raspberry Pi's main SPI bus, which is how
+    /// APA102 strips are usually wired up
+    fn default() -> Self {
+        Self {
+            bus: Bus::Spi0,
+            slave_select: SlaveSelect::Ss0,
+            mode: Mode::Mode0,
+            bit_order: BitOrder::MsbFirst,
+        }
+    }
+}
+
+/// Writes frames to the Raspberry Pi's SPI bus, keeping the device open
+/// between frames. If opening or writing fails the device is opened again on
+/// a later frame, waiting longer after each failure. Frames written while
+/// waiting are dropped
+pub struct SpiBackend {
+    clock_rate: u32,
+    config: SpiConfig,
+    spi: Option<Spi>,
+    retry_delay: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl SpiBackend {
+    pub fn new(clock_rate: u32, config: SpiConfig) -> Self {
+        Self {
+            clock_rate,
+            config,
+            spi: None,
+            retry_delay: INITIAL_RETRY_DELAY,
+            next_attempt: None,
+        }
+    }
+
+    fn open(&self) -> Result<Spi, rppal::spi::Error> {
+        let spi = Spi::new(
+            self.config.bus,
+            self.config.slave_select,
+            self.clock_rate,
+            self.config.mode,
+        )?;
+        // the Pi's SPI hardware only sends msb first, so lsb-first is done in
+        // software by `in_bit_order`
+        spi.set_bit_order(BitOrder::MsbFirst)?;
+        Ok(spi)
+    }
+
+    /// The buffer as it has to be sent for the configured bit order
+    fn in_bit_order<'a>(&self, buffer: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.config.bit_order {
+            BitOrder::MsbFirst => Cow::Borrowed(buffer),
+            BitOrder::LsbFirst => {
+                let mut reversed = buffer.to_vec();
+                reverse_bits(&mut reversed);
+                Cow::Owned(reversed)
+            }
+        }
+    }
+
+    /// Put off opening the device again, for longer each time
+    fn back_off(&mut self) {
+        self.spi = None;
+        self.next_attempt = Some(Instant::now() + self.retry_delay);
+        self.retry_delay = (self.retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+impl OutputBackend for SpiBackend {
+    fn write(&mut self, buffer: &[u8]) -> Result<(), OutputError> {
+        if self.spi.is_none() {
+            if self.next_attempt.is_some_and(|at| Instant::now() < at) {
+                return Ok(());
+            }
+            match self.open() {
+                Ok(spi) => self.spi = Some(spi),
+                Err(e) => {
+                    self.back_off();
+                    return Err(OutputError::Open(e));
+                }
+            }
+        }
+        let buffer = self.in_bit_order(buffer);
+        // only gets here with the device open
+        if let Err(e) = self.spi.as_mut().unwrap().write(&buffer) {
+            self.back_off();
+            return Err(OutputError::Write(e));
+        }
+        self.retry_delay = INITIAL_RETRY_DELAY;
+        self.next_attempt = None;
+        Ok(())
+    }
+
+    fn is_down(&self) -> bool {
+        self.next_attempt.is_some()
+    }
+
+    fn retry_in(&self) -> Option<Duration> {
+        self.next_attempt
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Keeps a copy of every buffer written to it, for testing without hardware.
+///
+/// Clones share the same recording, so keep a clone around before handing
+/// the backend to `Frames`.
+#[derive(Clone, Default)]
+pub struct RecordingBackend {
+    writes: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl RecordingBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn writes(&self) -> Vec<Vec<u8>> {
+        self.writes.lock().unwrap().clone()
+    }
+
+    pub fn last_write(&self) -> Option<Vec<u8>> {
+        self.writes.lock().unwrap().last().cloned()
+    }
+
+    pub fn clear(&self) {
+        self.writes.lock().unwrap().clear();
+    }
+}
+
+impl OutputBackend for RecordingBackend {
+    fn write(&mut self, buffer: &[u8]) -> Result<(), OutputError> {
+        self.writes.lock().unwrap().push(buffer.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spi_config() {
+        assert_eq!(
+            SpiConfig::new(0, 0, 0, "msb-first"),
+            Ok(SpiConfig::default())
+        );
+        assert_eq!(
+            SpiConfig::new(1, 2, 3, "LSB-first"),
+            Ok(SpiConfig {
+                bus: Bus::Spi1,
+                slave_select: SlaveSelect::Ss2,
+                mode: Mode::Mode3,
+                bit_order: BitOrder::LsbFirst,
+            })
+        );
+        assert!(SpiConfig::new(7, 0, 0, "msb-first").is_err());
+        assert!(SpiConfig::new(0, 16, 0, "msb-first").is_err());
+        assert!(SpiConfig::new(0, 0, 4, "msb-first").is_err());
+        assert!(SpiConfig::new(0, 0, 0, "middle-out").is_err());
+    }
+
+    #[test]
+    fn test_lsb_first_reversed_in_software() {
+        let buffer = [0xe1, 0x01, 0x80, 0x0f];
+        let msb = SpiBackend::new(15_000_000, SpiConfig::default());
+        assert_eq!(msb.in_bit_order(&buffer).as_ref(), buffer);
+        let config = SpiConfig::new(0, 0, 0, "lsb-first").unwrap();
+        let lsb = SpiBackend::new(15_000_000, config);
+        assert_eq!(lsb.in_bit_order(&buffer).as_ref(), [0x87, 0x80, 0x01, 0xf0]);
+    }
+
+    #[test]
+    fn test_backs_off_while_device_missing() {
+        // needs a machine without the SPI device, so that opening it fails
+        if std::path::Path::new("/dev/spidev0.0").exists() {
+            return;
+        }
+        let mut spi = SpiBackend::new(15_000_000, SpiConfig::default());
+        assert!(!spi.is_down());
+        assert!(matches!(spi.write(&[0; 4]), Err(OutputError::Open(_))));
+        assert!(spi.is_down());
+        assert!(spi
+            .retry_in()
+            .is_some_and(|delay| delay <= INITIAL_RETRY_DELAY));
+        // dropped without another attempt until the delay is up
+        assert!(spi.write(&[0; 4]).is_ok());
+        assert!(spi.is_down());
+        std::thread::sleep(INITIAL_RETRY_DELAY);
+        assert!(matches!(spi.write(&[0; 4]), Err(OutputError::Open(_))));
+        assert_eq!(spi.retry_delay, INITIAL_RETRY_DELAY * 4);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tokio::sync::watch;
+
+use crate::worker::{Scene, Status};
+
+pub fn load_scene(path: &Path) -> Result<Scene, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format!("Couldn't parse {}: {}", path.display(), e))
+}
+
+/// Save `scene` to `path`, writing it alongside first and then moving it into
+/// place so that a crash part way through leaves the old scene intact
+pub fn save_scene(path: &Path, scene: &Scene) -> Result<(), String> {
+    let contents = toml::to_string(scene).map_err(|e| e.to_string())?;
+    let temporary = path.with_extension("toml.tmp");
+    fs::write(&temporary, contents)
+        .map_err(|e| format!("Couldn't write {}: {}", temporary.display(), e))?;
+    fs::rename(&temporary, path).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))
+}
+
+/// Save the worker's scene to `path` every time it starts a new one, so that
+/// it can be played again after a restart. Runs until the worker goes away
+pub async fn save_scenes(mut status: watch::Receiver<Status>, path: PathBuf) {
+    let mut previous = status.borrow_and_update().scene.clone();
+    while status.changed().await.is_ok() {
+        let current = status.borrow_and_update().scene.clone();
+        if current == previous {
+            continue;
+        }
+        if let Some(scene) = &current {
+            let path = path.clone();
+            let scene = scene.clone();
+            let result = tokio::task::spawn_blocking(move || save_scene(&path, &scene)).await;
+            if let Ok(Err(e)) = result {
+                eprintln!("{}", e);
+            }
+        }
+        previous = current;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::colour::{BlendSpace, HueDirection};
+    use crate::easing::Easing;
+    use crate::frames::{LEDState, Step, Target};
+    use crate::worker::{Playback, Repeat};
+
+    #[test]
+    fn test_scene_round_trip() {
+        let red = LEDState::new(31, 255, 0, 0, 1.5);
+        let scene = Scene {
+            steps: vec![
+                red.into(),
+                Step {
+                    target: Target::Pixels(vec![red, LEDState::new(10, 0, 0, 255, 0.5)]),
+                    easing: Easing::CubicBezier(0.25, 0.1, 0.25, 1.0),
+                    blend: BlendSpace::Hsv(HueDirection::Decreasing),
+                },
+                Step {
+                    target: Target::Pixel(3, red),
+                    easing: Easing::EaseIn,
+                    blend: BlendSpace::Oklab,
+                },
+            ],
+            playback: Playback {
+                repeat: Repeat::Times(3),
+                ping_pong: true,
+            },
+        };
+        let path = std::env::temp_dir().join(format!("apa102-scene-{}.toml", std::process::id()));
+        save_scene(&path, &scene).unwrap();
+        let loaded = load_scene(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(scene));
+        assert!(!path.with_extension("toml.tmp").exists());
+    }
+
+    #[test]
+    fn test_loading_scene_saved_before_playback_modes() {
+        let path = std::env::temp_dir().join(format!("apa102-old-{}.toml", std::process::id()));
+        let red = LEDState::new(31, 255, 0, 0, 1.5);
+        let saved = Scene {
+            steps: vec![red.into()],
+            playback: Repeat::Forever.into(),
+        };
+        let contents = toml::to_string(&saved).unwrap();
+        let old: String = contents
+            .lines()
+            .filter(|l| !l.starts_with("playback"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        fs::write(&path, old).unwrap();
+        let loaded = load_scene(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(saved));
+    }
+
+    #[test]
+    fn test_missing_scene_is_an_error() {
+        assert!(load_scene(Path::new("/nonexistent/apa102-scene.toml")).is_err());
+    }
+}
@@ -1,47 +1,901 @@
-use crate::frames::{Frames, Interrupted, LEDState};
-use std::sync::mpsc::Receiver;
+use crate::frames::{Control, Controls, Frames, Interrupted, LEDState, Step};
+use crate::output::OutputError;
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, watch};
 
-#[derive(Clone)]
 pub enum Job {
-    OneOff(Vec<LEDState>),
-    Repeat(Vec<LEDState>),
+    OneOff(Vec<Step>),
+    Repeat(Vec<Step>),
+    /// Play through the steps as often and in the order `Playback` says
+    Playback(Vec<Step>, Playback),
+    /// As `OneOff`, sending back the event that ended the job once it
+    /// finishes, is interrupted or fails
+    OneOffAndWait(Vec<Step>, oneshot::Sender<Event>),
+    /// Play once over the top of the current job. If that job repeats it
+    /// carries on afterwards, from the start if the flag is set or otherwise
+    /// from the step it had reached
+    Notification(Vec<Step>, bool),
+}
+
+impl Job {
+    fn steps(&self) -> &[Step] {
+        match self {
+            Job::OneOff(steps)
+            | Job::Repeat(steps)
+            | Job::Playback(steps, _)
+            | Job::OneOffAndWait(steps, _)
+            | Job::Notification(steps, _) => steps,
+        }
+    }
+
+    fn repeats(&self) -> bool {
+        match self {
+            Job::Repeat(_) => true,
+            Job::Playback(_, playback) => playback.repeats(),
+            _ => false,
+        }
+    }
+
+    /// Let anyone waiting on the job know it won't be played after all
+    fn cancel(self) {
+        if let Job::OneOffAndWait(_, done) = self {
+            let _ = done.send(Event::JobInterrupted);
+        }
+    }
+}
+
+/// How many times a job plays through its steps
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Repeat {
+    #[default]
+    Once,
+    Forever,
+    Times(u32),
+    /// Start new loops until this time, finishing the one playing when it
+    /// passes
+    Until(SystemTime),
+}
+
+/// How a job plays through its steps. Saved as the same strings it is parsed
+/// from
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Playback {
+    pub repeat: Repeat,
+    /// Each loop plays forward through the steps and then back again
+    pub ping_pong: bool,
+}
+
+impl Playback {
+    pub fn repeats(&self) -> bool {
+        self.repeat != Repeat::Once
+    }
+
+    /// How many passes through `len` steps make up a loop
+    fn passes(&self, len: usize) -> u32 {
+        // there's nothing to turn around with a single step
+        if self.ping_pong && len > 1 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// The indices of `len` steps in the order pass number `pass` plays
+    /// them. Ping-pong passes don't play the step they turn around on twice
+    fn order(&self, len: usize, pass: u32) -> Vec<usize> {
+        match (self.passes(len), pass) {
+            (1, _) | (_, 0) => (0..len).collect(),
+            (_, p) if p % 2 == 1 => (0..len - 1).rev().collect(),
+            _ => (1..len).collect(),
+        }
+    }
+
+    /// Whether to start another loop after `loops` of them have finished
+    fn plays_again(&self, loops: u32) -> bool {
+        match self.repeat {
+            Repeat::Once => false,
+            Repeat::Forever => true,
+            Repeat::Times(n) => loops < n,
+            Repeat::Until(end) => SystemTime::now() < end,
+        }
+    }
+}
+
+impl From<Repeat> for Playback {
+    fn from(repeat: Repeat) -> Self {
+        Self {
+            repeat,
+            ping_pong: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParsePlaybackError(String);
+
+impl fmt::Display for ParsePlaybackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown playback mode {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParsePlaybackError {}
+
+impl FromStr for Playback {
+    type Err = ParsePlaybackError;
+
+    /// Parses `once`, `repeat`, `repeat <times>` or `until <unix time>`,
+    /// optionally preceded by `ping-pong`. An empty string, or `ping-pong` on
+    /// its own, is treated as once
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParsePlaybackError(s.to_string());
+        let lower = s.trim().to_ascii_lowercase();
+        let mut words = lower.split_whitespace().peekable();
+        let ping_pong = words.next_if_eq(&"ping-pong").is_some();
+        let repeat = match (words.next(), words.next(), words.next()) {
+            (None | Some("once"), None, None) => Repeat::Once,
+            (Some("repeat"), None, None) => Repeat::Forever,
+            (Some("repeat"), Some(n), None) => match n.parse() {
+                Ok(0) | Err(_) => return Err(err()),
+                Ok(n) => Repeat::Times(n),
+            },
+            (Some("until"), Some(t), None) => {
+                let secs = t.parse().map_err(|_| err())?;
+                let end = UNIX_EPOCH.checked_add(Duration::from_secs(secs));
+                Repeat::Until(end.ok_or_else(err)?)
+            }
+            _ => return Err(err()),
+        };
+        Ok(Self { repeat, ping_pong })
+    }
+}
+
+impl fmt::Display for Playback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ping_pong {
+            f.write_str("ping-pong ")?;
+        }
+        match self.repeat {
+            Repeat::Once => f.write_str("once"),
+            Repeat::Forever => f.write_str("repeat"),
+            Repeat::Times(n) => write!(f, "repeat {}", n),
+            Repeat::Until(end) => {
+                let secs = end.duration_since(UNIX_EPOCH).unwrap_or_default();
+                write!(f, "until {}", secs.as_secs())
+            }
+        }
+    }
 }
 
+impl From<Playback> for String {
+    fn from(playback: Playback) -> Self {
+        playback.to_string()
+    }
+}
+
+impl TryFrom<String> for Playback {
+    type Error = ParsePlaybackError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The steps of a job and how it plays them, without any of the plumbing for
+/// how it was requested. Kept so that the job can be played again later
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "SavedScene", into = "SavedScene")]
+pub struct Scene {
+    pub steps: Vec<Step>,
+    pub playback: Playback,
+}
+
+/// `Scene` as it is saved. Scenes saved before playback modes only say
+/// whether they repeat
+#[derive(Serialize, Deserialize)]
+struct SavedScene {
+    steps: Vec<Step>,
+    #[serde(default)]
+    repeat: bool,
+    playback: Option<Playback>,
+}
+
+impl From<SavedScene> for Scene {
+    fn from(saved: SavedScene) -> Self {
+        let repeat = if saved.repeat {
+            Repeat::Forever
+        } else {
+            Repeat::Once
+        };
+        Self {
+            steps: saved.steps,
+            playback: saved.playback.unwrap_or_else(|| repeat.into()),
+        }
+    }
+}
+
+impl From<Scene> for SavedScene {
+    fn from(scene: Scene) -> Self {
+        Self {
+            steps: scene.steps,
+            repeat: scene.playback.repeats(),
+            playback: Some(scene.playback),
+        }
+    }
+}
+
+impl From<Scene> for Job {
+    fn from(scene: Scene) -> Self {
+        Job::Playback(scene.steps, scene.playback)
+    }
+}
+
+/// How a new job fits in with the ones already playing or waiting to play
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum QueuePolicy {
+    /// Interrupt the current job and drop everything waiting
+    #[default]
+    Replace,
+    /// Play once everything before it has finished. Repeating jobs never
+    /// finish on their own, so this waits until they are replaced
+    Append,
+    /// As `Append`, but also have a repeating job stop at the end of its
+    /// current loop
+    AppendAfterLoop,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseQueuePolicyError(String);
+
+impl fmt::Display for ParseQueuePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown queue policy {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseQueuePolicyError {}
+
+impl FromStr for QueuePolicy {
+    type Err = ParseQueuePolicyError;
+
+    /// Parses `replace`, `append` or `append-after-current-loop`. An empty
+    /// string is treated as replace
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "replace" => Ok(Self::Replace),
+            "append" => Ok(Self::Append),
+            "append-after-current-loop" => Ok(Self::AppendAfterLoop),
+            _ => Err(ParseQueuePolicyError(s.to_string())),
+        }
+    }
+}
+
+/// Jobs waiting for the current one to finish, shared between the D-Bus
+/// interface and the worker
+#[derive(Default)]
+pub struct Queue {
+    jobs: Mutex<VecDeque<(Job, QueuePolicy)>>,
+}
+
+impl Queue {
+    /// Add a job to the back of the queue. Replacing jobs don't belong here
+    /// and go straight to the worker instead
+    pub fn push(&self, job: Job, policy: QueuePolicy) {
+        self.jobs.lock().unwrap().push_back((job, policy));
+    }
+
+    pub fn clear(&self) {
+        for (job, _) in self.jobs.lock().unwrap().drain(..) {
+            job.cancel();
+        }
+    }
+
+    /// Whether each waiting job repeats, how many steps it has and how long
+    /// one play through takes in seconds
+    pub fn summary(&self) -> Vec<(bool, u32, f32)> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(job, _)| {
+                let steps = job.steps();
+                (
+                    job.repeats(),
+                    steps.len() as u32,
+                    steps.iter().map(Step::duration).sum(),
+                )
+            })
+            .collect()
+    }
+
+    fn pop(&self) -> Option<Job> {
+        self.jobs.lock().unwrap().pop_front().map(|(job, _)| job)
+    }
+
+    /// Whether a repeating job should stop at the end of its current loop
+    fn ends_loop(&self) -> bool {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(_, policy)| *policy == QueuePolicy::AppendAfterLoop)
+    }
+}
+
+/// How long to wait after the output fails before playing the scene again
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
+/// How soon after panicking the worker has to panic again for the scene not
+/// to be played a second time
+const PANIC_WINDOW: Duration = Duration::from_secs(10);
+
+/// How often to write the frame again while the output is down, if the
+/// output can't say when it's worth trying
+const RETRY_POLL: Duration = Duration::from_millis(50);
+
+/// Whether the strip's output is working, and what has gone wrong with it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Health {
+    /// The last write failed and nothing has been written since
+    pub output_down: bool,
+    /// How many writes have failed since starting
+    pub errors: u32,
+    pub last_error: String,
+    /// How many times playing has panicked and been started again
+    pub worker_restarts: u32,
+}
+
+impl Health {
+    fn record(&mut self, error: &OutputError) {
+        self.output_down = true;
+        self.errors += 1;
+        self.last_error = error.to_string();
+    }
+}
+
+/// What the worker is currently doing, published for the D-Bus interface
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Status {
+    /// The state of each LED at the start of the current step, or once the
+    /// job has finished
+    pub leds: Vec<LEDState>,
+    pub running: bool,
+    pub repeating: bool,
+    /// The current job is holding its frame until resumed
+    pub paused: bool,
+    /// Index of the step currently playing within the job
+    pub step: u32,
+    /// The job playing or last played, leaving out notifications
+    pub scene: Option<Scene>,
+    pub health: Health,
+}
+
+/// Something that happened while running jobs, sent back to the D-Bus
+/// interface to be emitted as a signal
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    JobStarted,
+    /// The step with this index within the job has started
+    StepReached(u32),
+    JobFinished,
+    JobInterrupted,
+    OutputError(String),
+}
+
+/// Everything the D-Bus interface can ask of the worker
+pub enum Command {
+    /// Replace whatever is playing with the job
+    Play(Job),
+    /// A job has been pushed onto the queue, to start straight away if
+    /// nothing is playing
+    Enqueued,
+    /// Stop the current job, leaving the LEDs as they are
+    Stop,
+    /// Hold the current frame until resumed
+    Pause,
+    Resume,
+    /// Scale the output of every LED, from 0 to 1
+    SetBrightness(f32),
+    /// Play at this multiple of the normal speed, from `MIN_SPEED` to
+    /// `MAX_SPEED`
+    SetSpeed(f32),
+    /// Send back what the worker is doing
+    QueryState(oneshot::Sender<Status>),
+    /// Switch over to new `Frames` after a reload, carrying on with the
+    /// current step
+    Reload(Box<Frames>),
+    /// Stop playing and return
+    Shutdown,
+}
+
+/// The worker's end of the command channel. Commands that can't be acted on
+/// in the middle of a step are kept here until it ends
+struct Commands<'a> {
+    rx: Receiver<Command>,
+    status_tx: &'a watch::Sender<Status>,
+    /// The job to play once the current one has been interrupted
+    replacement: Option<Job>,
+    /// `Frames` to switch over to at the next chance, even part way through
+    /// a step
+    frames: Option<Box<Frames>>,
+    shutdown: bool,
+}
+
+impl Commands<'_> {
+    /// Act on `command` as far as possible, returning anything a playing
+    /// transition needs to do about it
+    fn handle(&mut self, command: Command) -> Option<Control> {
+        match command {
+            Command::Play(job) => {
+                if let Some(replaced) = self.replacement.replace(job) {
+                    replaced.cancel();
+                }
+                Some(Control::Interrupt)
+            }
+            Command::Stop => {
+                if let Some(replaced) = self.replacement.take() {
+                    replaced.cancel();
+                }
+                Some(Control::Interrupt)
+            }
+            Command::Pause => {
+                // only a playing job can be paused
+                self.status_tx.send_if_modified(|s| {
+                    let changed = s.running && !s.paused;
+                    s.paused |= s.running;
+                    changed
+                });
+                Some(Control::Pause)
+            }
+            Command::Resume => {
+                self.status_tx
+                    .send_if_modified(|s| std::mem::take(&mut s.paused));
+                Some(Control::Resume)
+            }
+            Command::SetBrightness(brightness) => Some(Control::MasterBrightness(brightness)),
+            Command::SetSpeed(speed) => Some(Control::Speed(speed)),
+            Command::Enqueued => None,
+            Command::QueryState(reply) => {
+                // the caller may have given up waiting
+                let _ = reply.send(self.status_tx.borrow().clone());
+                None
+            }
+            Command::Reload(frames) => {
+                self.frames = Some(frames);
+                None
+            }
+            Command::Shutdown => {
+                self.shutdown = true;
+                Some(Control::Interrupt)
+            }
+        }
+    }
+
+    /// Act on every command already sent, without waiting for more, applying
+    /// changes of plan straight to `frames`. Steps that take no time never
+    /// wait on the commands, so this keeps them from playing on regardless
+    fn poll(&mut self, frames: &mut Frames) -> Interrupted<(), OutputError> {
+        loop {
+            let control = match self.rx.try_recv() {
+                Ok(command) => self.handle(command),
+                Err(TryRecvError::Empty) => return Interrupted::No(Ok(())),
+                Err(TryRecvError::Disconnected) => self.handle(Command::Shutdown),
+            };
+            match control {
+                None => (),
+                Some(Control::Interrupt) => return Interrupted::Yes,
+                Some(Control::Pause) => frames.set_paused(true),
+                Some(Control::Resume) => frames.set_paused(false),
+                Some(Control::MasterBrightness(brightness)) => {
+                    frames.set_master_brightness(brightness);
+                    if let Err(e) = frames.output_frames() {
+                        return Interrupted::No(Err(e));
+                    }
+                }
+                Some(Control::Speed(speed)) => frames.set_speed(speed),
+            }
+        }
+    }
+
+    /// Switch over to the `Frames` sent with the last reload, if any. Returns
+    /// whether there was one
+    fn swap_frames(&mut self, frames: &mut Frames) -> Result<bool, OutputError> {
+        match self.frames.take() {
+            Some(new) => {
+                frames.switch_to(*new)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl Controls for Commands<'_> {
+    fn wait(&mut self, timeout: Option<Duration>) -> Option<Control> {
+        let result = match timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout),
+            None => self.rx.recv().map_err(RecvTimeoutError::from),
+        };
+        match result {
+            Ok(command) => self.handle(command),
+            Err(RecvTimeoutError::Timeout) => None,
+            // nobody is left to send anything to play
+            Err(RecvTimeoutError::Disconnected) => self.handle(Command::Shutdown),
+        }
+    }
+
+    fn reloaded(&mut self) -> Option<Frames> {
+        self.frames.take().map(|frames| *frames)
+    }
+}
+
+/// Look after the strip while there's no job to play: wait for a command and
+/// act on it, switch over to new `Frames` after a reload, and write the
+/// current frame again if the output is down to find out when it comes back
+fn idle(
+    frames: &mut Frames,
+    commands: &mut Commands,
+    status_tx: &watch::Sender<Status>,
+    event_tx: &mpsc::UnboundedSender<Event>,
+) {
+    // only a failed output needs looking at before the next command
+    let timeout = frames
+        .output_down()
+        .then(|| frames.retry_in().unwrap_or(RETRY_POLL));
+    // with nothing playing there's nothing to interrupt, pause or resume
+    let mut result = match commands.wait(timeout) {
+        Some(Control::MasterBrightness(brightness)) => {
+            frames.set_master_brightness(brightness);
+            frames.output_frames()
+        }
+        Some(Control::Speed(speed)) => {
+            frames.set_speed(speed);
+            Ok(())
+        }
+        _ => Ok(()),
+    };
+    if result.is_ok() {
+        result = match commands.swap_frames(frames) {
+            Ok(false) if frames.output_down() => frames.output_frames(),
+            other => other.map(|_| ()),
+        };
+        // switching over changes the LEDs even if showing them failed
+        status_tx.send_if_modified(|s| {
+            let changed = s.leds != frames.state();
+            if changed {
+                s.leds = frames.state().to_vec();
+            }
+            changed
+        });
+    }
+    match result {
+        Ok(()) => {
+            let down = frames.output_down();
+            status_tx.send_if_modified(|s| {
+                let changed = s.health.output_down != down;
+                s.health.output_down = down;
+                changed
+            });
+        }
+        Err(e) => {
+            status_tx.send_modify(|s| s.health.record(&e));
+            let _ = event_tx.send(Event::OutputError(e.to_string()));
+        }
+    }
+}
+
+/// Where to pick a job back up from: the pass through its steps and the step
+/// reached within that pass
+#[derive(Debug, Clone, Copy, Default)]
+struct Resume {
+    pass: u32,
+    step: usize,
+}
+
+/// Play the steps at each index in `order`
+fn play_steps(
+    frames: &mut Frames,
+    steps: &[Step],
+    order: impl IntoIterator<Item = usize>,
+    commands: &mut Commands,
+    status_tx: &watch::Sender<Status>,
+    event_tx: &mpsc::UnboundedSender<Event>,
+) -> Interrupted<(), OutputError> {
+    for i in order {
+        match commands.poll(frames) {
+            Interrupted::No(Ok(())) => (),
+            other => return other,
+        }
+        // new settings take effect between steps so the job carries on
+        if let Err(e) = commands.swap_frames(frames) {
+            return Interrupted::No(Err(e));
+        }
+        status_tx.send_modify(|s| {
+            s.step = i as u32;
+            s.leds = frames.state().to_vec();
+            s.health.output_down = frames.output_down();
+        });
+        let _ = event_tx.send(Event::StepReached(i as u32));
+        match frames.transition(&steps[i], commands) {
+            Interrupted::No(Ok(())) => (),
+            other => return other,
+        };
+    }
+    Interrupted::No(Ok(()))
+}
+
+/// Play jobs and act on the other commands sent over `commands`. Jobs played
+/// that way replace whatever is playing, and once nothing is left the next
+/// job is taken from `queue`.
+///
+/// Failing to write to the strip ends the current job, after which the last
+/// scene is played again unless another job has arrived. While the output is
+/// down and nothing is playing, the current frame is written again to find
+/// out when it comes back. A panic while playing is caught and counted in
+/// the status' health, and the last scene played again unless it has only
+/// just panicked. Runs until told to shut down or every sender has gone
 pub fn update_leds(
     frames: &mut Frames,
-    job_rx: Receiver<Job>,
-    interrupt_rx: Receiver<bool>,
-) -> Result<(), rppal::spi::Error> {
+    commands: Receiver<Command>,
+    queue: &Queue,
+    status_tx: watch::Sender<Status>,
+    event_tx: mpsc::UnboundedSender<Event>,
+) {
+    status_tx.send_modify(|s| s.leds = frames.state().to_vec());
+    let mut commands = Commands {
+        rx: commands,
+        status_tx: &status_tx,
+        replacement: None,
+        frames: None,
+        shutdown: false,
+    };
+    let mut restart = None;
+    let mut last_panic: Option<Instant> = None;
     loop {
-        for job in job_rx.try_iter() {
-            // ignore any interrupts we receive before starting to prevent
-            // premature exits
-            let _ = interrupt_rx.try_recv();
-            match job {
-                Job::OneOff(v) => {
-                    for target in v {
-                        match frames.transition(&target, &interrupt_rx) {
-                            Interrupted::Yes => break,
-                            Interrupted::No(x) => x?,
-                        };
-                    }
+        let played = panic::catch_unwind(AssertUnwindSafe(|| {
+            play_jobs(
+                frames,
+                &mut commands,
+                queue,
+                &status_tx,
+                &event_tx,
+                restart.take(),
+            )
+        }));
+        if played.is_ok() {
+            return;
+        }
+        frames.release();
+        frames.set_paused(false);
+        status_tx.send_modify(|s| {
+            s.running = false;
+            s.repeating = false;
+            s.paused = false;
+            s.leds = frames.state().to_vec();
+            s.health.worker_restarts += 1;
+        });
+        let _ = event_tx.send(Event::JobInterrupted);
+        thread::sleep(RESTART_DELAY);
+        // if the scene itself is what panics, playing it again would only
+        // panic over and over
+        if last_panic.is_none_or(|at| at.elapsed() >= PANIC_WINDOW) {
+            restart = status_tx.borrow().scene.clone();
+        }
+        last_panic = Some(Instant::now());
+    }
+}
+
+/// The body of `update_leds`, starting with `restart` if there is a scene to
+/// pick back up
+fn play_jobs(
+    frames: &mut Frames,
+    commands: &mut Commands,
+    queue: &Queue,
+    status_tx: &watch::Sender<Status>,
+    event_tx: &mpsc::UnboundedSender<Event>,
+    mut restart: Option<Scene>,
+) {
+    // the job to play next, and where to start it from
+    let mut next: Option<(Job, Resume)> = None;
+    // a repeating job cut short by a notification, to go back to once the
+    // notification has finished
+    let mut preempted: Option<(Vec<Step>, Playback, Resume)> = None;
+    loop {
+        if commands.shutdown {
+            return;
+        }
+        let (job, resume) = match next.take() {
+            Some(next) => next,
+            None => match commands
+                .replacement
+                .take()
+                .or_else(|| restart.take().map(Job::from))
+                .or_else(|| queue.pop())
+            {
+                Some(job) => (job, Resume::default()),
+                None => {
+                    idle(frames, commands, status_tx, event_tx);
+                    continue;
                 }
-                Job::Repeat(v) => loop {
-                    let mut breaker: bool = false;
-                    for target in &v {
-                        match frames.transition(target, &interrupt_rx) {
-                            Interrupted::Yes => {
-                                breaker = true;
-                                break;
-                            }
-                            Interrupted::No(x) => x?,
-                        };
+            },
+        };
+        let notification = matches!(job, Job::Notification(..));
+        if !notification {
+            preempted = None;
+        }
+        let (steps, playback, done) = match job {
+            Job::OneOff(v) | Job::Notification(v, _) => (v, Playback::default(), None),
+            Job::Repeat(v) => (v, Repeat::Forever.into(), None),
+            Job::Playback(v, playback) => (v, playback, None),
+            Job::OneOffAndWait(v, done) => (v, Playback::default(), Some(done)),
+        };
+        let repeat = playback.repeats();
+        status_tx.send_modify(|s| {
+            s.running = true;
+            s.repeating = repeat;
+            if !notification {
+                s.scene = Some(Scene {
+                    steps: steps.clone(),
+                    playback,
+                });
+            }
+        });
+        frames.claim();
+        // nobody listening for events isn't a reason to stop the lights
+        let _ = event_tx.send(Event::JobStarted);
+        let passes = playback.passes(steps.len());
+        let mut pass = resume.pass;
+        let mut first = Some(resume.step);
+        let result = loop {
+            let mut order = playback.order(steps.len(), pass);
+            // a job picked back up after a notification skips the steps it
+            // had already played in the pass it was on
+            if let Some(first) = first.take() {
+                let reached = order.iter().position(|&i| i == first).unwrap_or(0);
+                order.drain(..reached);
+            }
+            // a pass with no steps to play has to look at the commands itself
+            match commands.poll(frames) {
+                Interrupted::No(Ok(())) => (),
+                other => break other,
+            }
+            match play_steps(frames, &steps, order, commands, status_tx, event_tx) {
+                Interrupted::No(Ok(())) => {
+                    pass += 1;
+                    // a ping-pong loop always comes back to the start
+                    let loop_ended = pass % passes == 0;
+                    if loop_ended && (!playback.plays_again(pass / passes) || queue.ends_loop()) {
+                        break Interrupted::No(Ok(()));
                     }
-                    if breaker {
-                        break;
+                }
+                other => break other,
+            }
+        };
+        frames.release();
+        frames.set_paused(false);
+        let step = status_tx.borrow().step as usize;
+        status_tx.send_modify(|s| {
+            s.running = false;
+            s.repeating = false;
+            s.paused = false;
+            s.leds = frames.state().to_vec();
+            match &result {
+                Interrupted::No(Err(e)) => s.health.record(e),
+                _ => s.health.output_down = frames.output_down(),
+            }
+        });
+        let event = match &result {
+            Interrupted::Yes => Event::JobInterrupted,
+            Interrupted::No(Ok(())) => Event::JobFinished,
+            Interrupted::No(Err(e)) => Event::OutputError(e.to_string()),
+        };
+        let _ = event_tx.send(event.clone());
+        if let Some(done) = done {
+            // the caller may have given up waiting
+            let _ = done.send(event);
+        }
+        match result {
+            // interrupted by a new job, which has to play before anything
+            // from the queue, or stopped
+            Interrupted::Yes => match commands.replacement.take() {
+                Some(job) => {
+                    if let Job::Notification(_, from_start) = job {
+                        // a ping-pong job still has its way back to play
+                        if repeat || passes > 1 {
+                            // going back to the start of the current loop
+                            // still counts the loops already played
+                            let resume = if from_start {
+                                Resume {
+                                    pass: pass - pass % passes,
+                                    step: 0,
+                                }
+                            } else {
+                                Resume { pass, step }
+                            };
+                            preempted = Some((steps, playback, resume));
+                        }
                     }
-                },
+                    next = Some((job, Resume::default()));
+                }
+                None => preempted = None,
+            },
+            Interrupted::No(Ok(())) if notification => {
+                next = preempted
+                    .take()
+                    .map(|(steps, playback, resume)| (Job::Playback(steps, playback), resume));
+            }
+            Interrupted::No(Ok(())) => (),
+            Interrupted::No(Err(_)) => {
+                thread::sleep(RESTART_DELAY);
+                restart = status_tx.borrow().scene.clone();
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_queue_policy() {
+        assert_eq!("".parse(), Ok(QueuePolicy::Replace));
+        assert_eq!("Append".parse(), Ok(QueuePolicy::Append));
+        assert_eq!(
+            "append-after-current-loop".parse(),
+            Ok(QueuePolicy::AppendAfterLoop)
+        );
+        assert!("prepend".parse::<QueuePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_playback() {
+        assert_eq!("".parse(), Ok(Playback::default()));
+        assert_eq!("Repeat".parse(), Ok(Playback::from(Repeat::Forever)));
+        assert_eq!(
+            "ping-pong repeat 3".parse(),
+            Ok(Playback {
+                repeat: Repeat::Times(3),
+                ping_pong: true,
+            })
+        );
+        let until = "until 1767225600".parse::<Playback>().unwrap();
+        assert_eq!(until.to_string(), "until 1767225600");
+        assert!("until 18446744073709551615".parse::<Playback>().is_err());
+        assert!("repeat 0".parse::<Playback>().is_err());
+        assert!("repeat forever".parse::<Playback>().is_err());
+        assert!("pong-ping".parse::<Playback>().is_err());
+    }
+
+    #[test]
+    fn test_ping_pong_order() {
+        let playback = Playback {
+            repeat: Repeat::Forever,
+            ping_pong: true,
+        };
+        assert_eq!(playback.order(3, 0), vec![0, 1, 2]);
+        assert_eq!(playback.order(3, 1), vec![1, 0]);
+        assert_eq!(playback.order(3, 2), vec![1, 2]);
+        assert_eq!(playback.passes(1), 1);
+        assert_eq!(playback.order(1, 1), vec![0]);
+    }
+
+    #[test]
+    fn test_clearing_queue_cancels_waiting_jobs() {
+        let queue = Queue::default();
+        let (done_tx, mut done_rx) = oneshot::channel();
+        queue.push(Job::OneOffAndWait(vec![], done_tx), QueuePolicy::Append);
+        queue.clear();
+        assert_eq!(done_rx.try_recv(), Ok(Event::JobInterrupted));
+    }
+}
@@ -7,6 +7,30 @@ sleep 2
 busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionHex 'a(syd)b' 2 "ff0000" 255 1.0 "00ff00" 255 1.0 true
 sleep 2
 
+echo Check transition eased and transition hex eased
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionEased 'a((yyyyd)s)b' 2 255 255 0 0 1.0 "ease-in" 0 0 0 0 1.0 "cubic-bezier(0.25, 0.1, 0.25, 1)" false
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionHexEased 'a(syds)b' 2 "ff0000" 255 1.0 "sine" "000000" 255 1.0 "step" false
+sleep 2
+
+echo Check blended transitions and HSV
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionBlended 'a(yyyyd)bs' 2 255 255 0 0 1.0 255 0 0 255 1.0 false "hsv"
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionHexBlended 'a(syd)bs' 2 "ff0000" 255 1.0 "0000ff" 255 1.0 false "hsl-increasing"
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionEasedBlended 'a((yyyyd)s)bs' 1 255 0 255 0 1.0 "sine" false "hsv-decreasing"
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionBlended 'a(yyyyd)bs' 2 255 0 0 255 1.0 255 0 255 0 1.0 false "oklab"
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionBlended 'a(yyyyd)bs' 2 255 255 0 0 1.0 255 0 0 255 1.0 false "oklch"
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 SetHsv 'dddyd' 240 1.0 1.0 255 1.0
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 FlashHsv 'dddyd' 0 1.0 1.0 255 1.0
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 PulseHsv 'dddyd' 120 1.0 1.0 255 1.0
+sleep 4
+
 echo Check flash and flash hex
 busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Flash '(yyyyd)' 255 255 0 0 1.0
 sleep 2
@@ -25,7 +49,109 @@ sleep 2
 busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 SetHex 'syd' "ff0000" 255 1.0
 sleep 2
 
+echo Check set pixel and set pixels
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 SetPixel 'q(yyyyd)' 0 255 0 255 0 1.0
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 SetPixelHex 'qsyd' 0 "0000ff" 255 1.0
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 SetPixels 'a(yyyyd)' 2 255 255 0 0 1.0 255 0 255 0 1.0
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 SetPixelsHex 'a(syd)' 2 "00ff00" 255 1.0 "ff0000" 255 1.0
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionPixels 'aa(yyyyd)b' 2 2 255 255 0 0 1.0 255 0 0 255 1.0 1 255 0 255 0 1.0 false
+sleep 3
+
 echo Check rainbow
 busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Rainbow 'ydb' 255 1.0 true
 sleep 10
 busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+
+echo Check segments, assuming a segment called shelf is configured
+busctl --user introspect org.zbus.apa102 /org/zbus/apa102/segments/shelf org.zbus.apa102
+busctl --user call org.zbus.apa102 /org/zbus/apa102/segments/shelf org.zbus.apa102 Pulse '(yyyyd)' 255 0 0 255 1.0
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 SetPixel 'q(yyyyd)' 0 255 255 0 0 1.0
+sleep 4
+busctl --user call org.zbus.apa102 /org/zbus/apa102/segments/shelf org.zbus.apa102 Clear
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+
+echo Check properties
+busctl --user introspect org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Pulse '(yyyyd)' 255 0 0 255 1.0
+sleep 1
+busctl --user get-property org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Running Repeating Step Health
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+sleep 2
+busctl --user get-property org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Leds
+
+echo Check signals
+busctl --user monitor --match "type='signal',interface='org.zbus.apa102'" &
+MONITOR=$!
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Flash '(yyyyd)' 255 0 0 255 1.0
+sleep 3
+kill $MONITOR
+
+echo Check waiting for jobs to finish
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionAndWait 'a(yyyyd)' 2 255 255 0 0 1.0 255 0 0 255 1.0
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 FlashHexAndWait 'syd' "00ff00" 255 1.0
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 FlashAndWait '(yyyyd)' 255 255 0 0 5.0 &
+sleep 1
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+wait
+
+echo Check queueing
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Enqueue 'a(yyyyd)bs' 2 255 255 0 0 1.0 255 0 0 255 1.0 true replace
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Enqueue 'a(yyyyd)bs' 1 255 0 255 0 1.0 false append
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 GetQueue
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 ClearQueue
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Enqueue 'a(yyyyd)bs' 1 255 0 255 0 1.0 false append-after-current-loop
+sleep 4
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+
+echo Check notifications
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Rainbow 'ydb' 255 1.0 true
+sleep 3
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 NotifyFlash '(yyyyd)' 255 255 255 255 0.5
+sleep 4
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 NotifyFlashHex 'syd' "ff0000" 255 0.5
+sleep 4
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+
+echo Check reloading the config
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Pulse '(yyyyd)' 255 0 0 255 1.0
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Reload
+sleep 3
+pkill -HUP apa102-dbus
+sleep 3
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+
+echo Check pausing and stopping
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Rainbow 'ydb' 255 1.0 true
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Pause
+busctl --user get-property org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Paused
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Resume
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Stop
+sleep 2
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+
+echo Check speed and master brightness
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Rainbow 'ydb' 255 2.0 true
+sleep 2
+busctl --user set-property org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Speed d 4
+sleep 2
+busctl --user set-property org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 MasterBrightness d 0.2
+sleep 2
+busctl --user get-property org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Speed MasterBrightness
+busctl --user set-property org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Speed d 1
+busctl --user set-property org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 MasterBrightness d 1
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
+
+echo Check playback modes
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionEx 'a(yyyyd)s' 3 255 255 0 0 0.5 255 0 255 0 0.5 255 0 0 255 0.5 'ping-pong repeat 2'
+sleep 4
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionEx 'a(yyyyd)s' 2 255 255 0 0 0.5 0 0 0 0 0.5 "until $(( $(date +%s) + 3 ))"
+sleep 4
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 TransitionEx 'a(yyyyd)s' 0 'repeat' && echo "Empty repeating job wasn't rejected"
+busctl --user call org.zbus.apa102 /org/zbus/apa102 org.zbus.apa102 Clear
@@ -1,44 +1,585 @@
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::Duration;
 
+use tokio::sync::{mpsc as async_mpsc, oneshot, watch};
+
+use apa102_dbus::output::{OutputBackend, OutputError, RecordingBackend};
 use apa102_dbus::{frames, worker};
 
+fn expected_buffer(num_leds: usize, led: [u8; 4]) -> Vec<u8> {
+    let mut buffer = vec![0; 4];
+    for _ in 0..num_leds {
+        buffer.extend(led);
+    }
+    buffer.extend([0xff; 4]);
+    buffer
+}
+
+/// Start a worker playing on `num_leds` LEDs written to `output`, taking
+/// queued jobs from `queue`
+fn spawn_worker(
+    num_leds: u16,
+    output: impl OutputBackend + 'static,
+    queue: Arc<worker::Queue>,
+) -> (
+    mpsc::Sender<worker::Command>,
+    watch::Receiver<worker::Status>,
+    async_mpsc::UnboundedReceiver<worker::Event>,
+    thread::JoinHandle<()>,
+) {
+    let (command_tx, command_rx) = mpsc::channel();
+    let (status_tx, status_rx) = watch::channel(worker::Status::default());
+    let (event_tx, event_rx) = async_mpsc::unbounded_channel();
+    let thread = thread::spawn(move || {
+        worker::update_leds(
+            &mut frames::Frames::new(num_leds, 5, Box::new(output)),
+            command_rx,
+            &queue,
+            status_tx,
+            event_tx,
+        );
+    });
+    (command_tx, status_rx, event_rx, thread)
+}
+
+/// Fails a run of writes part way through, recording the rest
+struct FlakyBackend {
+    recording: RecordingBackend,
+    writes: usize,
+    failing: std::ops::Range<usize>,
+    down: bool,
+}
+
+impl OutputBackend for FlakyBackend {
+    fn write(&mut self, buffer: &[u8]) -> Result<(), OutputError> {
+        self.writes += 1;
+        self.down = self.failing.contains(&self.writes);
+        if self.down {
+            let error = std::io::Error::other("unplugged");
+            return Err(OutputError::Write(rppal::spi::Error::Io(error)));
+        }
+        self.recording.write(buffer)
+    }
+
+    fn is_down(&self) -> bool {
+        self.down
+    }
+}
+
+/// Panics on one write, recording the rest
+struct PanickingBackend {
+    recording: RecordingBackend,
+    writes: usize,
+    panic_on: usize,
+}
+
+impl OutputBackend for PanickingBackend {
+    fn write(&mut self, buffer: &[u8]) -> Result<(), OutputError> {
+        self.writes += 1;
+        if self.writes == self.panic_on {
+            panic!("write {} went wrong", self.writes);
+        }
+        self.recording.write(buffer)
+    }
+}
+
 #[test]
 fn test_round_trip_via_threads() {
     let num_leds = 5;
-    let clock_rate = 1_500_000;
-    let (job_tx, job_rx) = mpsc::channel();
-    let (interrupt_tx, interrupt_rx) = mpsc::channel();
-    #[allow(unused_must_use)]
-    thread::spawn(move || {
-        worker::update_leds(
-            &mut frames::Frames::new(num_leds, clock_rate, 5),
-            job_rx,
-            interrupt_rx,
+    let output = RecordingBackend::new();
+    let (command_tx, status_rx, mut event_rx, _worker) =
+        spawn_worker(num_leds, output.clone(), Arc::default());
+
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.2);
+    let green = frames::LEDState::new(255, 0, 255, 0, 0.2);
+    let blue = frames::LEDState::new(255, 0, 0, 255, 0.2);
+    let clear = frames::LEDState::new(0, 0, 0, 0, 0.2);
+    let sequence: Vec<frames::Step> = vec![red.into(), green.into(), blue.into(), clear.into()];
+
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::OneOff(sequence.clone())))
+        .is_ok());
+    thread::sleep(Duration::from_secs(1));
+    let writes = output.writes();
+    assert!(writes.contains(&expected_buffer(5, [0xff, 0x00, 0x00, 0xff])));
+    assert!(writes.contains(&expected_buffer(5, [0xff, 0x00, 0xff, 0x00])));
+    assert!(writes.contains(&expected_buffer(5, [0xff, 0xff, 0x00, 0x00])));
+    assert_eq!(
+        output.last_write(),
+        Some(expected_buffer(5, [0xe0, 0x00, 0x00, 0x00]))
+    );
+    assert!(!status_rx.borrow().running);
+    assert_eq!(status_rx.borrow().leds, vec![clear; 5]);
+    let mut events = vec![];
+    while let Ok(event) = event_rx.try_recv() {
+        events.push(event);
+    }
+    assert_eq!(
+        events,
+        vec![
+            worker::Event::JobStarted,
+            worker::Event::StepReached(0),
+            worker::Event::StepReached(1),
+            worker::Event::StepReached(2),
+            worker::Event::StepReached(3),
+            worker::Event::JobFinished,
+        ]
+    );
+
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::Repeat(sequence)))
+        .is_ok());
+    thread::sleep(Duration::from_secs(2));
+    assert!(status_rx.borrow().running);
+    assert!(status_rx.borrow().repeating);
+
+    let purple = frames::LEDState::new(255, 255, 0, 255, 0.2);
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::OneOff(vec![
+            purple.into()
+        ])))
+        .is_ok());
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(
+        output.last_write(),
+        Some(expected_buffer(5, [0xff, 0xff, 0x00, 0xff]))
+    );
+    let mut events = vec![];
+    while let Ok(event) = event_rx.try_recv() {
+        events.push(event);
+    }
+    assert!(events.contains(&worker::Event::JobInterrupted));
+    assert_eq!(events.last(), Some(&worker::Event::JobFinished));
+
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::OneOff(vec![
+            clear.into()
+        ])))
+        .is_ok());
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(
+        output.last_write(),
+        Some(expected_buffer(5, [0xe0, 0x00, 0x00, 0x00]))
+    );
+}
+
+#[test]
+fn test_waiting_for_one_off_jobs() {
+    let (command_tx, _status_rx, _event_rx, _worker) =
+        spawn_worker(3, RecordingBackend::new(), Arc::default());
+
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.2);
+    let (done_tx, done_rx) = oneshot::channel();
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::OneOffAndWait(
+            vec![red.into()],
+            done_tx
+        )))
+        .is_ok());
+    assert_eq!(done_rx.blocking_recv(), Ok(worker::Event::JobFinished));
+
+    let slow = frames::LEDState::new(255, 0, 0, 255, 5.0);
+    let (done_tx, done_rx) = oneshot::channel();
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::OneOffAndWait(
+            vec![slow.into()],
+            done_tx
+        )))
+        .is_ok());
+    thread::sleep(Duration::from_millis(200));
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::OneOff(vec![red.into()])))
+        .is_ok());
+    assert_eq!(done_rx.blocking_recv(), Ok(worker::Event::JobInterrupted));
+}
+
+#[test]
+fn test_queued_jobs() {
+    let queue = Arc::new(worker::Queue::default());
+    let (command_tx, status_rx, _event_rx, _worker) =
+        spawn_worker(3, RecordingBackend::new(), queue.clone());
+
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.2);
+    let blue = frames::LEDState::new(255, 0, 0, 255, 0.2);
+    let green = frames::LEDState::new(255, 0, 255, 0, 0.2);
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::Repeat(vec![
+            red.into(),
+            blue.into()
+        ])))
+        .is_ok());
+    thread::sleep(Duration::from_millis(100));
+
+    // a plain append waits behind a repeating job
+    queue.push(
+        worker::Job::OneOff(vec![green.into()]),
+        worker::QueuePolicy::Append,
+    );
+    assert!(command_tx.send(worker::Command::Enqueued).is_ok());
+    thread::sleep(Duration::from_millis(600));
+    assert!(status_rx.borrow().repeating);
+    assert_eq!(queue.summary(), vec![(false, 1, 0.2)]);
+    queue.clear();
+    assert!(queue.summary().is_empty());
+
+    // but can ask it to stop at the end of its loop
+    let (done_tx, done_rx) = oneshot::channel();
+    queue.push(
+        worker::Job::OneOffAndWait(vec![green.into()], done_tx),
+        worker::QueuePolicy::AppendAfterLoop,
+    );
+    assert!(command_tx.send(worker::Command::Enqueued).is_ok());
+    assert_eq!(done_rx.blocking_recv(), Ok(worker::Event::JobFinished));
+    assert_eq!(status_rx.borrow().leds, vec![green; 3]);
+
+    // and queued jobs play back to back
+    let (done_tx, done_rx) = oneshot::channel();
+    queue.push(
+        worker::Job::OneOff(vec![red.into()]),
+        worker::QueuePolicy::Append,
+    );
+    queue.push(
+        worker::Job::OneOffAndWait(vec![blue.into()], done_tx),
+        worker::QueuePolicy::Append,
+    );
+    assert!(command_tx.send(worker::Command::Enqueued).is_ok());
+    assert_eq!(done_rx.blocking_recv(), Ok(worker::Event::JobFinished));
+    assert_eq!(status_rx.borrow().leds, vec![blue; 3]);
+}
+
+#[test]
+fn test_notifications_resume_repeating_jobs() {
+    let (command_tx, status_rx, mut event_rx, _worker) =
+        spawn_worker(3, RecordingBackend::new(), Arc::default());
+    let mut drain_events = || {
+        let mut events = vec![];
+        while let Ok(event) = event_rx.try_recv() {
+            events.push(event);
+        }
+        events
+    };
+
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.2);
+    let green = frames::LEDState::new(255, 0, 255, 0, 0.2);
+    let blue = frames::LEDState::new(255, 0, 0, 255, 0.2);
+    let white = frames::LEDState::new(255, 255, 255, 255, 0.2);
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::Repeat(vec![
+            red.into(),
+            green.into(),
+            blue.into()
+        ])))
+        .is_ok());
+    // part way through the green step
+    thread::sleep(Duration::from_millis(300));
+    drain_events();
+
+    for (from_start, resumed_step) in [(false, 1), (true, 0)] {
+        assert!(command_tx
+            .send(worker::Command::Play(worker::Job::Notification(
+                vec![white.into()],
+                from_start
+            )))
+            .is_ok());
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(
+            drain_events(),
+            vec![
+                worker::Event::JobInterrupted,
+                worker::Event::JobStarted,
+                worker::Event::StepReached(0),
+                worker::Event::JobFinished,
+                worker::Event::JobStarted,
+                worker::Event::StepReached(resumed_step),
+            ]
         );
-    });
+        assert!(status_rx.borrow().repeating);
+        assert_eq!(status_rx.borrow().leds, vec![white; 3]);
+    }
+}
 
-    let red = frames::LEDState::new(255, 0, 0, 255, 1.0);
-    let green = frames::LEDState::new(255, 0, 255, 0, 1.0);
-    let blue = frames::LEDState::new(255, 255, 0, 0, 1.0);
-    let clear = frames::LEDState::new(0, 0, 0, 0, 1.0);
+#[test]
+fn test_swapping_frames_keeps_job_running() {
+    let first_output = RecordingBackend::new();
+    let second_output = RecordingBackend::new();
+    let (command_tx, status_rx, _event_rx, _worker) =
+        spawn_worker(3, first_output.clone(), Arc::default());
 
-    assert!(job_tx
-        .send(worker::Job::OneOff(vec![red, green, blue, clear]))
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.2);
+    let blue = frames::LEDState::new(255, 0, 0, 255, 0.2);
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::Repeat(vec![
+            red.into(),
+            blue.into()
+        ])))
+        .is_ok());
+    thread::sleep(Duration::from_millis(300));
+    assert!(command_tx
+        .send(worker::Command::Reload(Box::new(frames::Frames::new(
+            5,
+            5,
+            Box::new(second_output.clone())
+        ))))
         .is_ok());
-    thread::sleep(Duration::from_secs(5));
+    thread::sleep(Duration::from_millis(500));
+    assert!(status_rx.borrow().repeating);
+    assert_eq!(status_rx.borrow().leds.len(), 5);
+    let writes_before = first_output.writes().len();
+    thread::sleep(Duration::from_millis(200));
+    // everything now goes to the new output, at its new length
+    assert_eq!(first_output.writes().len(), writes_before);
+    let writes = second_output.writes();
+    assert!(writes.contains(&expected_buffer(5, [0xff, 0x00, 0x00, 0xff])));
+    assert!(writes.contains(&expected_buffer(5, [0xff, 0xff, 0x00, 0x00])));
+}
+
+#[test]
+fn test_restarting_after_output_errors() {
+    let output = RecordingBackend::new();
+    let worker_output = FlakyBackend {
+        recording: output.clone(),
+        writes: 0,
+        failing: 10..13,
+        down: false,
+    };
+    let (command_tx, status_rx, mut event_rx, _worker) =
+        spawn_worker(3, worker_output, Arc::default());
 
-    assert!(job_tx
-        .send(worker::Job::Repeat(vec![red, green, blue, clear]))
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.2);
+    let blue = frames::LEDState::new(255, 0, 0, 255, 0.2);
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::Repeat(vec![
+            red.into(),
+            blue.into()
+        ])))
         .is_ok());
-    thread::sleep(Duration::from_secs(10));
+    thread::sleep(Duration::from_millis(200));
+    // the job stops with the first failure and is played again after a delay
+    assert!(status_rx.borrow().health.output_down);
+    assert!(!status_rx.borrow().running);
+    thread::sleep(Duration::from_millis(1800));
+    let health = status_rx.borrow().health.clone();
+    assert!(!health.output_down);
+    assert_eq!(health.errors, 3);
+    assert!(health.last_error.contains("unplugged"));
+    assert!(status_rx.borrow().repeating);
+    let writes_before = output.writes().len();
+    thread::sleep(Duration::from_millis(100));
+    assert!(output.writes().len() > writes_before);
+    let mut errors = 0;
+    while let Ok(event) = event_rx.try_recv() {
+        if let worker::Event::OutputError(_) = event {
+            errors += 1;
+        }
+    }
+    assert_eq!(errors, 3);
+}
 
-    let purple = frames::LEDState::new(255, 255, 0, 255, 1.0);
-    assert!(job_tx.send(worker::Job::OneOff(vec![purple])).is_ok());
-    assert!(interrupt_tx.send(true).is_ok());
-    thread::sleep(Duration::from_secs(3));
+#[test]
+fn test_controlling_the_worker() {
+    let output = RecordingBackend::new();
+    let (command_tx, _status_rx, mut event_rx, worker) =
+        spawn_worker(3, output.clone(), Arc::default());
+    let query = || {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        assert!(command_tx
+            .send(worker::Command::QueryState(reply_tx))
+            .is_ok());
+        reply_rx.blocking_recv().unwrap()
+    };
 
-    assert!(job_tx.send(worker::Job::OneOff(vec![clear])).is_ok());
-    thread::sleep(Duration::from_secs(2));
+    let red = frames::LEDState::new(31, 255, 0, 0, 1.0);
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::OneOff(vec![red.into()])))
+        .is_ok());
+    thread::sleep(Duration::from_millis(300));
+    assert!(command_tx.send(worker::Command::Pause).is_ok());
+    thread::sleep(Duration::from_millis(50));
+    // paused part way through the fade, with nothing more written
+    let paused = output.last_write().unwrap();
+    let writes = output.writes().len();
+    thread::sleep(Duration::from_millis(1000));
+    assert!(query().running);
+    assert!(query().paused);
+    assert_eq!(output.writes().len(), writes);
+    assert!((64..192).contains(&paused[7]), "{:?}", paused);
+
+    assert!(command_tx.send(worker::Command::Resume).is_ok());
+    thread::sleep(Duration::from_millis(200));
+    assert!(query().running);
+    assert!(!query().paused);
+    assert_ne!(output.last_write().unwrap(), paused);
+
+    // stopping leaves the LEDs where they had got to
+    assert!(command_tx.send(worker::Command::Stop).is_ok());
+    thread::sleep(Duration::from_millis(100));
+    let stopped = output.last_write().unwrap();
+    assert!(!query().running);
+    assert!(stopped[7] > paused[7] && stopped[7] < 255, "{:?}", stopped);
+    let mut events = vec![];
+    while let Ok(event) = event_rx.try_recv() {
+        events.push(event);
+    }
+    assert_eq!(events.last(), Some(&worker::Event::JobInterrupted));
+
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::OneOff(vec![
+            frames::LEDState::new(31, 255, 0, 0, 0.0).into()
+        ])))
+        .is_ok());
+    thread::sleep(Duration::from_millis(100));
+    assert!(command_tx.send(worker::Command::SetBrightness(0.5)).is_ok());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        output.last_write(),
+        Some(expected_buffer(3, [0xff, 0x00, 0x00, 0x80]))
+    );
+
+    assert!(command_tx.send(worker::Command::Shutdown).is_ok());
+    assert!(worker.join().is_ok());
+}
+
+#[test]
+fn test_playback_modes() {
+    let (command_tx, status_rx, mut event_rx, worker) =
+        spawn_worker(3, RecordingBackend::new(), Arc::default());
+    let mut steps_until_finished = || {
+        let mut steps = vec![];
+        loop {
+            match event_rx.blocking_recv() {
+                Some(worker::Event::StepReached(i)) => steps.push(i),
+                Some(worker::Event::JobFinished) => return steps,
+                Some(worker::Event::JobStarted) => (),
+                other => panic!("Unexpected event {:?}", other),
+            }
+        }
+    };
+
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.05);
+    let green = frames::LEDState::new(255, 0, 255, 0, 0.05);
+    let blue = frames::LEDState::new(255, 0, 0, 255, 0.05);
+    let steps = vec![red.into(), green.into(), blue.into()];
+    let play = |mode: &str| {
+        let playback = mode.parse().unwrap();
+        let job = worker::Job::Playback(steps.clone(), playback);
+        assert!(command_tx.send(worker::Command::Play(job)).is_ok());
+    };
+
+    play("repeat 2");
+    assert_eq!(steps_until_finished(), vec![0, 1, 2, 0, 1, 2]);
+    assert_eq!(status_rx.borrow().leds, vec![blue; 3]);
+
+    // each loop goes there and back, ending where it started
+    play("ping-pong repeat 2");
+    assert_eq!(steps_until_finished(), vec![0, 1, 2, 1, 0, 1, 2, 1, 0]);
+    assert_eq!(status_rx.borrow().leds, vec![red; 3]);
+
+    // a time already passed still finishes the first loop
+    play("until 0");
+    assert_eq!(steps_until_finished(), vec![0, 1, 2]);
+
+    assert!(command_tx.send(worker::Command::Shutdown).is_ok());
+    worker.join().unwrap();
+}
+
+#[test]
+fn test_restarting_after_panics() {
+    let output = RecordingBackend::new();
+    let worker_output = PanickingBackend {
+        recording: output.clone(),
+        writes: 0,
+        panic_on: 5,
+    };
+    let (command_tx, status_rx, _event_rx, worker) = spawn_worker(3, worker_output, Arc::default());
+
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.2);
+    let blue = frames::LEDState::new(255, 0, 0, 255, 0.2);
+    assert!(command_tx
+        .send(worker::Command::Play(worker::Job::Repeat(vec![
+            red.into(),
+            blue.into()
+        ])))
+        .is_ok());
+    // the scene plays again once the worker has recovered
+    thread::sleep(Duration::from_millis(800));
+    assert_eq!(status_rx.borrow().health.worker_restarts, 1);
+    assert!(status_rx.borrow().repeating);
+    let writes_before = output.writes().len();
+    thread::sleep(Duration::from_millis(100));
+    assert!(output.writes().len() > writes_before);
+
+    assert!(command_tx.send(worker::Command::Shutdown).is_ok());
+    assert!(worker.join().is_ok());
+}
+
+#[test]
+fn test_notifications_keep_playback_progress() {
+    let (command_tx, _status_rx, mut event_rx, worker) =
+        spawn_worker(3, RecordingBackend::new(), Arc::default());
+    // the steps reached by the job, then by the notification, then once the
+    // job has been picked back up
+    let mut steps_per_job = || {
+        let mut jobs = vec![];
+        let mut steps = vec![];
+        while jobs.len() < 3 {
+            match event_rx.blocking_recv() {
+                Some(worker::Event::StepReached(i)) => steps.push(i),
+                Some(worker::Event::JobInterrupted | worker::Event::JobFinished) => {
+                    jobs.push(std::mem::take(&mut steps))
+                }
+                Some(worker::Event::JobStarted) => (),
+                other => panic!("Unexpected event {:?}", other),
+            }
+        }
+        jobs
+    };
+
+    let red = frames::LEDState::new(255, 255, 0, 0, 0.2);
+    let green = frames::LEDState::new(255, 0, 255, 0, 0.2);
+    let blue = frames::LEDState::new(255, 0, 0, 255, 0.2);
+    let white = frames::LEDState::new(255, 255, 255, 255, 0.05);
+    let steps = vec![red.into(), green.into(), blue.into()];
+    let interrupt = |mode: &str, after: u64| {
+        let job = worker::Job::Playback(steps.clone(), mode.parse().unwrap());
+        assert!(command_tx.send(worker::Command::Play(job)).is_ok());
+        thread::sleep(Duration::from_millis(after));
+        let notification = worker::Job::Notification(vec![white.into()], false);
+        assert!(command_tx.send(worker::Command::Play(notification)).is_ok());
+    };
+
+    // part way through the green step of the second loop, which is the last
+    interrupt("repeat 2", 900);
+    assert_eq!(
+        steps_per_job(),
+        vec![vec![0, 1, 2, 0, 1], vec![0], vec![1, 2]]
+    );
+
+    // on the way back through the green step
+    interrupt("ping-pong", 700);
+    assert_eq!(steps_per_job(), vec![vec![0, 1, 2, 1], vec![0], vec![1, 0]]);
+
+    assert!(command_tx.send(worker::Command::Shutdown).is_ok());
+    assert!(worker.join().is_ok());
+}
+
+#[test]
+fn test_stopping_jobs_that_take_no_time() {
+    let output = RecordingBackend::new();
+    let (command_tx, status_rx, _event_rx, worker) =
+        spawn_worker(2, output.clone(), Arc::default());
+    let red = frames::LEDState::new(31, 255, 0, 0, 0.0);
+    let blue = frames::LEDState::new(31, 0, 0, 255, 0.0);
+    let instant = vec![red.into(), blue.into()];
+    for steps in [instant, vec![]] {
+        let job = worker::Job::Repeat(steps);
+        assert!(command_tx.send(worker::Command::Play(job)).is_ok());
+        thread::sleep(Duration::from_millis(100));
+        assert!(status_rx.borrow().running);
+        assert!(command_tx.send(worker::Command::Stop).is_ok());
+        thread::sleep(Duration::from_millis(100));
+        assert!(!status_rx.borrow().running);
+    }
+    assert!(command_tx.send(worker::Command::Shutdown).is_ok());
+    assert!(worker.join().is_ok());
 }